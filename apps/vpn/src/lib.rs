@@ -2,6 +2,23 @@ use cryptoxide::chacha20poly1305::ChaCha20Poly1305;
 use lz4_flex::{compress_prepend_size, decompress_size_prepended};
 use std::ffi::c_int;
 
+/// Allocates `len` bytes, used by the host to pass the input and output of
+/// a call.
+#[no_mangle]
+pub extern "C" fn alloc(len: c_int) -> c_int {
+    let mut buf = Vec::<u8>::with_capacity(len as usize);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+
+    ptr as c_int
+}
+
+/// Frees `len` bytes at `ptr` returned by `alloc`.
+#[no_mangle]
+pub extern "C" fn dealloc(ptr: c_int, len: c_int) {
+    unsafe { drop(Vec::from_raw_parts(ptr as *mut u8, 0, len as usize)) };
+}
+
 #[no_mangle]
 pub extern "C" fn comp(in_offset: c_int, len: c_int, out_offset: c_int) -> c_int {
     let in_bytes = unsafe { std::slice::from_raw_parts(in_offset as *const u8, len as usize) };
@@ -91,7 +91,7 @@ fn main() -> anyhow::Result<()> {
     let threashold_ns = 500000;
 
     println!("[invk] starting to receive messages: {}", n_message);
-    for i in 0..n_message {
+    for (i, txtime) in messages_txtime.iter_mut().enumerate() {
         let bytes = rx.recv().unwrap();
 
         let current_request_time_ns = std::time::SystemTime::now()
@@ -158,7 +158,7 @@ fn main() -> anyhow::Result<()> {
                     .unwrap()
                     .as_nanos();
 
-                messages_txtime[i] = now;
+                *txtime = now;
                 let exec_time = now - last_request_time_ns;
 
                 average_exec_time = (average_exec_time * i as u128 + exec_time) / (i + 1) as u128;
@@ -0,0 +1,286 @@
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Export ending a chain: no module is called, the invoker reports the
+/// end-to-end latency of the chain instead.
+pub const TIME_EXPORT: &str = "time";
+
+/// A function of a chain, invoked for the INVOKs of `topic`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Function {
+    pub topic: String,
+    /// Compiled WASM module exporting the function, unused by `time`.
+    #[serde(default)]
+    pub module: Option<String>,
+    pub export: String,
+    /// Topics the output is forwarded to, the chain ends when empty.
+    #[serde(default)]
+    pub next: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    functions: Vec<Function>,
+}
+
+/// The function of every topic served by the invoker, from a TOML manifest
+/// reloaded when it changes.
+pub struct ChainRegistry {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    functions: HashMap<String, Function>,
+}
+
+impl ChainRegistry {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let modified = modified(&path);
+        let functions = parse(&path)?;
+
+        Ok(ChainRegistry {
+            path,
+            modified,
+            functions,
+        })
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&Function> {
+        self.functions.get(topic)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Reloads the manifest if it was modified since the last load, returns
+    /// whether it did. The current chains are kept if the new manifest is
+    /// invalid.
+    pub fn reload_if_changed(&mut self) -> anyhow::Result<bool> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return Ok(false);
+        }
+
+        // NOTE: the timestamp is taken before parsing, a broken manifest is
+        //       only reported once and retried when it changes again.
+        self.modified = modified;
+        self.functions = parse(&self.path)?;
+
+        Ok(true)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn parse(path: &Path) -> anyhow::Result<HashMap<String, Function>> {
+    let buf = fs::read_to_string(path)
+        .with_context(|| format!("unable to read chain manifest {}", path.display()))?;
+    let manifest: Manifest = toml::from_str(&buf)
+        .with_context(|| format!("invalid chain manifest {}", path.display()))?;
+
+    let mut functions = HashMap::new();
+    for function in manifest.functions {
+        if function.module.is_none() && function.export != TIME_EXPORT {
+            bail!(
+                "{}: function '{}' of topic '{}' has no module",
+                path.display(),
+                function.export,
+                function.topic
+            );
+        }
+        if let Some(previous) = functions.insert(function.topic.clone(), function) {
+            bail!(
+                "{}: topic '{}' is served by more than one function",
+                path.display(),
+                previous.topic
+            );
+        }
+    }
+
+    for function in functions.values() {
+        if let Some(next) = function
+            .next
+            .iter()
+            .find(|next| !functions.contains_key(*next))
+        {
+            bail!(
+                "{}: function '{}' of topic '{}' forwards to unknown topic '{}'",
+                path.display(),
+                function.export,
+                function.topic,
+                next
+            );
+        }
+    }
+
+    Ok(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// A manifest file removed when dropped.
+    struct ManifestFile(PathBuf);
+
+    impl ManifestFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "tempos-chains-{}-{}.toml",
+                name,
+                std::process::id()
+            ));
+            let manifest = ManifestFile(path);
+            manifest.write(content, SystemTime::now());
+            manifest
+        }
+
+        /// Replaces the content, with an explicit modification time so that
+        /// the change is seen regardless of the timestamp resolution.
+        fn write(&self, content: &str, modified: SystemTime) {
+            fs::write(&self.0, content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&self.0)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    impl Drop for ManifestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const CHAIN: &str = r#"
+        [[functions]]
+        topic = "vpn"
+        module = "final.so"
+        export = "comp"
+        next = ["out"]
+
+        [[functions]]
+        topic = "out"
+        export = "time"
+    "#;
+
+    fn load_err(name: &str, content: &str) -> String {
+        let manifest = ManifestFile::new(name, content);
+        format!("{:#}", ChainRegistry::load(&manifest.0).err().unwrap())
+    }
+
+    #[test]
+    fn load() {
+        let manifest = ManifestFile::new("load", CHAIN);
+        let chains = ChainRegistry::load(&manifest.0).unwrap();
+
+        assert_eq!(chains.len(), 2);
+        let vpn = chains.get("vpn").unwrap();
+        assert_eq!(vpn.module.as_deref(), Some("final.so"));
+        assert_eq!(vpn.export, "comp");
+        assert_eq!(vpn.next, ["out"]);
+        let out = chains.get("out").unwrap();
+        assert_eq!(out.module, None);
+        assert!(out.next.is_empty());
+        assert!(chains.get("enc").is_none());
+    }
+
+    #[test]
+    fn shipped_manifest_is_valid() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../config/chains.toml");
+        let chains = ChainRegistry::load(path).unwrap();
+        assert_eq!(chains.len(), 5);
+    }
+
+    #[test]
+    fn missing_manifest() {
+        let path = std::env::temp_dir().join("tempos-chains-missing.toml");
+        let err = format!("{:#}", ChainRegistry::load(path).err().unwrap());
+        assert!(err.contains("unable to read chain manifest"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        let err = load_err("invalid", "[[functions]]\ntopic = \"vpn\"\n");
+        assert!(err.contains("invalid chain manifest"), "{}", err);
+        let err = load_err("unknown-key", &format!("{}retries = 3\n", CHAIN));
+        assert!(err.contains("invalid chain manifest"), "{}", err);
+    }
+
+    #[test]
+    fn missing_module() {
+        let err = load_err(
+            "missing-module",
+            &CHAIN.replace("module = \"final.so\"", ""),
+        );
+        assert!(
+            err.contains("function 'comp' of topic 'vpn' has no module"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn duplicate_topic() {
+        let err = load_err("duplicate", &format!("{}{}", CHAIN, CHAIN));
+        assert!(
+            err.contains("is served by more than one function"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_next() {
+        let err = load_err(
+            "unknown-next",
+            &CHAIN.replace("[\"out\"]", "[\"out\", \"enc\"]"),
+        );
+        assert!(
+            err.contains("function 'comp' of topic 'vpn' forwards to unknown topic 'enc'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reload_keeps_the_chains_of_an_invalid_manifest() {
+        let manifest = ManifestFile::new("reload", CHAIN);
+        let mut chains = ChainRegistry::load(&manifest.0).unwrap();
+        let start = SystemTime::now();
+        assert!(!chains.reload_if_changed().unwrap());
+
+        manifest.write(
+            &CHAIN.replace("[\"out\"]", "[\"enc\"]"),
+            start + Duration::from_secs(1),
+        );
+        assert!(chains.reload_if_changed().is_err());
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains.get("vpn").unwrap().next, ["out"]);
+        // NOTE: a broken manifest is only reported once.
+        assert!(!chains.reload_if_changed().unwrap());
+
+        manifest.write(
+            &CHAIN.replace("export = \"comp\"", "export = \"encrypt\""),
+            start + Duration::from_secs(2),
+        );
+        assert!(chains.reload_if_changed().unwrap());
+        assert_eq!(chains.get("vpn").unwrap().export, "encrypt");
+        assert!(!chains.reload_if_changed().unwrap());
+    }
+}
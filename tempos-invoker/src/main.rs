@@ -1,15 +1,28 @@
+use chains::{ChainRegistry, TIME_EXPORT};
 use clap::Parser;
+use mio::{unix::SourceFd, Events, Interest, Poll, Token};
 use std::{
-    collections::HashMap,
-    io::Write,
-    net::{self, SocketAddr},
-    sync::{atomic::AtomicBool, Arc},
+    io,
+    os::unix::prelude::*,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
     thread,
     time::{Duration, Instant},
 };
 use sysinfo::{CpuExt, System, SystemExt};
-use wasmer::{imports, Engine, Imports, Instance, Module, Store, Value};
-
+use tempos::buffer::{Buffer, BufferPool};
+use tempos::endpoint::{Endpoint, PeerAddr, TemposSocket};
+use tempos::message::{decode, max_invok_data_len, TemposHeader, TemposMessage, MAX_MESSAGE_LEN};
+use tempos::shm::ShmRing;
+use tempos::txtime::{TxTimeErrors, TxTimeScheduler, TxTimeSocket};
+#[cfg(feature = "uring")]
+use tempos::uring::UringSocket;
+use wasm::{InvocationError, WASMInvoker};
+
+mod chains;
+mod wasm;
 // mod invokers;
 
 /// Simple TEMPOS Invoker example
@@ -21,7 +34,7 @@ struct Args {
     node: u32,
     #[clap(short, long)]
     topics: String,
-    /// address to send the message to
+    /// address of the MOM lane, ip:port or unix:/path
     #[clap(short, long)]
     saddr: String,
     #[clap(short, long, default_value = "0")]
@@ -29,50 +42,140 @@ struct Args {
 
     #[clap(short, long, default_value = "false")]
     warm: bool,
+
+    /// interval between two load reports to the MOM, in milliseconds
+    #[clap(short = 'i', long, default_value = "1000")]
+    monitor_interval: u64,
+
+    /// also receive invocations through a shared-memory ring, for a MOM on the same host
+    #[clap(long)]
+    shm: bool,
+
+    /// spin on the socket instead of sleeping until it is readable, trading a core for latency
+    #[clap(long)]
+    busy_poll: bool,
+
+    /// pin the invocation loop to this CPU
+    #[clap(long)]
+    cpu: Option<usize>,
+
+    /// forward results with SO_TXTIME through the etf/taprio qdisc of this interface
+    #[clap(long)]
+    txtime_iface: Option<String>,
+
+    /// SO_PRIORITY of the forwarded results, selects their taprio traffic class
+    #[clap(long, default_value = "3")]
+    txtime_priority: i32,
+
+    /// taprio cycle time, in nanoseconds
+    #[clap(long, default_value = "900000")]
+    txtime_cycle_ns: u64,
+
+    /// taprio base-time, start of the first cycle, in nanoseconds
+    #[clap(long, default_value = "0")]
+    txtime_base_ns: u64,
+
+    /// start of the window of the priority in the taprio cycle, in nanoseconds
+    #[clap(long, default_value = "0")]
+    txtime_offset_ns: u64,
+
+    /// length of the window of the priority, in nanoseconds
+    #[clap(long, default_value = "300000")]
+    txtime_window_ns: u64,
+
+    /// end of the window in which no result is launched anymore, in nanoseconds
+    #[clap(long, default_value = "0")]
+    txtime_guard_ns: u64,
+
+    /// clockid of the etf/taprio qdisc, the launch times are expressed in it
+    #[clap(long, default_value = "CLOCK_TAI")]
+    txtime_clock: String,
+
+    /// TOML manifest of the function chains, reloaded when it changes
+    #[clap(long, default_value = "config/chains.toml")]
+    chains: String,
+
+    /// memory the cached WASM modules and their instances may use, in MiB
+    #[clap(long, default_value = "256")]
+    module_budget_mb: usize,
+
+    /// memory an instance may grow to before it is dropped, in MiB
+    #[clap(long, default_value = "64")]
+    instance_memory_mb: usize,
+}
+
+/// State shared between the invocation loop and the monitoring loop.
+#[derive(Default)]
+struct InvokerState {
+    in_flight: AtomicU32,
+    warm: AtomicBool,
 }
 
 pub fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
-    let saddr: SocketAddr = args.saddr.parse()?;
+    let saddr = args.saddr.parse::<Endpoint>()?.peer().ok_or_else(|| {
+        anyhow::anyhow!("the MOM lane {} must be a datagram endpoint", args.saddr)
+    })?;
 
-    let address = std::env::var("INVKADDR")?;
+    let address: Endpoint = std::env::var("INVKADDR")?.parse()?;
 
     log::info!("Starting TEMPOS Invoker {} on {}", args.node, address);
 
-    let sock = net::UdpSocket::bind(address)?;
+    let sock = TemposSocket::bind(&address)?;
     // sock.set_read_timeout(Some(Duration::from_millis(100)))?;
     sock.set_nonblocking(true)?;
 
     // init sysinfo
     let sys = System::new_all();
 
+    // NOTE: the ring must exist before the registration, the MOM opens it
+    //       as soon as it is announced.
+    let ring = if args.shm {
+        let path = tempos::shm::ring_path(args.node);
+        let ring = ShmRing::create(
+            &path,
+            tempos::shm::DEFAULT_SLOTS,
+            tempos::shm::DEFAULT_SLOT_SIZE,
+        )?;
+        log::info!("receiving invocations through {}", path.display());
+        Some(ring)
+    } else {
+        None
+    };
+
+    let chains = ChainRegistry::load(&args.chains)?;
+    log::info!("{} functions loaded from {}", chains.len(), args.chains);
+
     let topics = args.topics.split(",").collect::<Vec<&str>>();
-    for (i, topic) in topics.iter().enumerate() {
+    for topic in &topics {
         log::debug!("registering topic: {}", topic);
-        register_topic(topic, args.node, &sock, saddr)?;
+        register_topic(topic, args.node, &sock, &saddr, ring.is_some())?;
     }
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
     ctrlc::set_handler(move || {
-        r.store(false, std::sync::atomic::Ordering::Relaxed);
+        r.store(false, Ordering::Relaxed);
     })?;
 
     let r = running.clone();
+    let state = Arc::new(InvokerState::default());
 
     let args2 = args.clone();
+    let state2 = state.clone();
+    let saddr2 = saddr.clone();
     let main_thread = thread::spawn(move || {
-        main_loop(r, sock, &args2, saddr);
+        main_loop(r, sock, ring, chains, &args2, saddr2, state2);
     });
 
     let r = running.clone();
 
-    // tokio::runtime::Builder::new_current_thread()
-    //     .enable_all()
-    //     .build()?
-    //     .block_on(monitoring_loop(r, sys, args, addr));
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(monitoring_loop(r, sys, args, saddr, state))?;
 
     main_thread.join().unwrap();
 
@@ -84,293 +187,658 @@ pub fn main() -> anyhow::Result<()> {
 fn register_topic(
     topic: &str,
     node: u32,
-    sock: &net::UdpSocket,
-    saddr: SocketAddr,
+    sock: &TemposSocket,
+    saddr: &PeerAddr,
+    shm: bool,
 ) -> anyhow::Result<()> {
     let mut buf_send: Vec<u8> = Vec::with_capacity(1024);
 
-    buf_send.write(&tempos::msg_type::REGISTRATION.to_be_bytes())?;
-    buf_send.write(&node.to_be_bytes())?;
+    let mut header = TemposHeader::now();
+    if shm {
+        header.flags |= tempos::flags::SHM_RING;
+    }
 
-    let topic_len = topic.len() as u32;
-    buf_send.write(&topic_len.to_be_bytes())?;
-    buf_send.write(&topic.as_bytes())?;
+    TemposMessage::Registration {
+        node_id: node,
+        topic,
+    }
+    .encode(&header, &mut buf_send);
 
     sock.send_to(&buf_send, saddr)?;
 
     Ok(())
 }
 
-async fn monitoring_loop(r: Arc<AtomicBool>, mut sys: System, args: Args, addr: SocketAddr) {
-    let sock = tokio::net::UdpSocket::bind("127.0.0.1".parse::<SocketAddr>().unwrap())
-        .await
-        .unwrap();
+async fn monitoring_loop(
+    r: Arc<AtomicBool>,
+    mut sys: System,
+    args: Args,
+    addr: PeerAddr,
+    state: Arc<InvokerState>,
+) -> anyhow::Result<()> {
+    // NOTE: reports are small datagrams, a blocking send does not stall the runtime.
+    let sock = TemposSocket::unbound_for(&addr)?;
+    let interval = Duration::from_millis(args.monitor_interval);
     let mut buf = Vec::with_capacity(1024);
-    while r.load(std::sync::atomic::Ordering::Relaxed) {
+    while r.load(Ordering::Relaxed) {
         sys.refresh_cpu();
+        sys.refresh_memory();
 
         let mut load = 0.0;
         sys.cpus().iter().for_each(|cpu| load += cpu.cpu_usage());
         load = (load / sys.cpus().len() as f32) / 100.0;
 
-        log::trace!("load: {}", load);
+        let memory = sys.used_memory() as f32 / sys.total_memory().max(1) as f32;
+        let in_flight = state.in_flight.load(Ordering::Relaxed);
+        let warm = state.warm.load(Ordering::Relaxed);
 
-        buf.write(&tempos::msg_type::MONITORING.to_be_bytes())
-            .unwrap();
-        buf.write(&args.node.to_be_bytes()).unwrap();
-        buf.write(&load.to_be_bytes()).unwrap();
+        log::trace!(
+            "load: {}, memory: {}, in_flight: {}, warm: {}",
+            load,
+            memory,
+            in_flight,
+            warm
+        );
 
-        sock.send_to(&mut buf, addr).await.unwrap();
+        buf.clear();
+        TemposMessage::Monitoring {
+            node_id: args.node,
+            load,
+            memory,
+            in_flight,
+            warm,
+        }
+        .encode(&TemposHeader::now(), &mut buf);
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        if let Err(e) = sock.send_to(&buf, &addr) {
+            log::warn!("failed to send load report: {}", e);
+        }
 
-        buf.clear();
+        tokio::time::sleep(interval).await;
     }
-}
 
-struct WASMInvoker {
-    engine: Engine,
-    store: Store,
-    module: Option<Module>,
-    import_object: Option<Imports>,
-    instance: Option<Instance>,
+    Ok(())
 }
 
-impl WASMInvoker {
-    pub fn new() -> Self {
-        let engine = Engine::headless();
-        let store = Store::new(&engine);
-
-        WASMInvoker {
-            engine,
-            store,
-            module: None,
-            import_object: None,
-            instance: None,
-        }
+/// Longest reason carried by a FAILURE, the rest is cut.
+const MAX_REASON_LEN: usize = 256;
+
+/// Reports the failure of `invok` to the MOM, which passes it on to the
+/// sender of the INVOK.
+fn report_failure(
+    transport: &mut Transport,
+    addr: &PeerAddr,
+    buf: &mut Buffer,
+    header: &TemposHeader,
+    invok: &TemposMessage,
+    error: &InvocationError,
+) {
+    let (seq, chain, hop, topic) = match invok {
+        TemposMessage::Invok {
+            seq,
+            chain,
+            hop,
+            topic,
+            ..
+        } => (*seq, *chain, *hop, *topic),
+        _ => return,
+    };
+
+    let reason = error.to_string();
+    let mut end = reason.len().min(MAX_REASON_LEN);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
     }
 
-    pub fn load(&mut self, path: &str) -> anyhow::Result<()> {
-        self.unload();
+    buf.clear();
+    let encoded = TemposMessage::Failure {
+        seq,
+        chain,
+        hop,
+        kind: error.kind(),
+        status: error.status(),
+        topic,
+        reason: &reason[..end],
+    }
+    .encode_into(header, buf);
+    if let Err(e) = encoded {
+        log::error!("unable to report the failure of message {}: {}", seq, e);
+        return;
+    }
 
-        log::debug!("loading module: {}", path);
+    if let Err(e) = transport.send_to(buf.as_slice(), addr) {
+        log::error!("failed to report the failure of message {}: {}", seq, e);
+    }
+}
 
-        let module = unsafe { Module::deserialize_from_file(&self.store, path)? };
-        let import_object = imports! {};
+/// Longest wait for a datagram before the loop reports that it is idle.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+/// Longest wait of io_uring when invocations also come through the
+/// shared-memory ring, whose wakeup it does not watch.
+#[cfg(feature = "uring")]
+const SHM_WAIT: Duration = Duration::from_micros(10);
+/// Time without invocations after which the WASM instances are dropped. The
+/// loop may report that it is idle far more often, on every empty spin with
+/// --busy-poll or every SHM_WAIT with io_uring and --shm.
+const COOL_DOWN_AFTER: Duration = Duration::from_millis(100);
+/// How often the chain manifest is checked for changes.
+const CHAINS_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the launch-time reports of the qdisc are collected.
+const TXTIME_ERRORS_INTERVAL: Duration = Duration::from_secs(1);
+
+const SOCKET: Token = Token(0);
+const RING: Token = Token(1);
+
+/// Receive and forward path of the main loop.
+enum Transport {
+    /// Nonblocking socket polled in a tight loop: lowest latency, but the
+    /// loop keeps its core busy.
+    Busy(TemposSocket),
+    /// Nonblocking socket, the loop sleeps in epoll until it or the
+    /// wakeup of the ring is readable.
+    Event {
+        sock: TemposSocket,
+        poll: Poll,
+        events: Events,
+        wait: Duration,
+    },
+    /// io_uring waiting in the kernel for the next datagram.
+    #[cfg(feature = "uring")]
+    Uring {
+        // NOTE: kept for its Drop, the ring owns a reference to the socket.
+        _sock: TemposSocket,
+        uring: Box<UringSocket>,
+        wait: Duration,
+    },
+}
 
-        log::debug!("instantiating module: {}", path);
+impl Transport {
+    fn new(sock: TemposSocket, ring: Option<&ShmRing>, busy_poll: bool) -> io::Result<Self> {
+        if busy_poll {
+            log::info!("busy polling the socket");
+            return Ok(Transport::Busy(sock));
+        }
 
-        let instance = Instance::new(&mut self.store, &module, &import_object)?;
+        #[cfg(feature = "uring")]
+        match UringSocket::new(
+            &sock,
+            tempos::uring::DEFAULT_RECV_BUFFERS,
+            tempos::uring::DEFAULT_SEND_SLOTS,
+        ) {
+            Ok(uring) => {
+                log::info!("using io_uring");
+                return Ok(Transport::Uring {
+                    _sock: sock,
+                    uring: Box::new(uring),
+                    wait: if ring.is_some() { SHM_WAIT } else { IDLE_WAIT },
+                });
+            }
+            Err(e) => log::warn!("io_uring unavailable, using epoll: {}", e),
+        }
 
-        self.module = Some(module);
-        self.import_object = Some(import_object);
-        self.instance = Some(instance);
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut SourceFd(&sock.as_raw_fd()), SOCKET, Interest::READABLE)?;
+        if let Some(ring) = ring {
+            poll.registry()
+                .register(&mut SourceFd(&ring.wakeup_fd()), RING, Interest::READABLE)?;
+        }
 
-        Ok(())
+        Ok(Transport::Event {
+            sock,
+            poll,
+            events: Events::with_capacity(2),
+            wait: IDLE_WAIT,
+        })
     }
 
-    pub fn unload(&mut self) {
-        if let Some(instance) = &self.instance {
-            drop(instance);
-            self.instance = None;
+    /// Receives the next message into `buf`, fails with `WouldBlock` when
+    /// there is none. A wait is cut short by a message pushed to `ring`,
+    /// which is left in the ring.
+    fn recv(&mut self, buf: &mut Buffer, ring: Option<&ShmRing>) -> io::Result<Option<PeerAddr>> {
+        match self {
+            Transport::Busy(sock) => recv_into(sock, buf),
+            Transport::Event {
+                sock,
+                poll,
+                events,
+                wait,
+            } => match recv_into(sock, buf) {
+                // NOTE: readiness is edge-triggered, only wait once the socket
+                //       has been drained.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if ring.is_none_or(ShmRing::prepare_wait) {
+                        let polled = poll.poll(events, Some(*wait));
+                        if let Some(ring) = ring {
+                            ring.finish_wait();
+                        }
+                        match polled {
+                            Ok(()) => {}
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    recv_into(sock, buf)
+                }
+                received => received,
+            },
+            // NOTE: the ring is not watched by io_uring, invocations sitting
+            //       in it can wait up to SHM_WAIT.
+            #[cfg(feature = "uring")]
+            Transport::Uring { uring, wait, .. } => uring.recv(buf, *wait),
         }
+    }
 
-        if let Some(import_object) = &self.import_object {
-            drop(import_object);
-            self.import_object = None;
+    fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+        match self {
+            Transport::Busy(sock) | Transport::Event { sock, .. } => {
+                sock.send_to(buf, peer).map(|_| ())
+            }
+            #[cfg(feature = "uring")]
+            Transport::Uring { uring, .. } => uring.send_to(buf, peer),
         }
+    }
 
-        if let Some(module) = &self.module {
-            drop(module);
-            self.module = None;
+    /// Called when nothing was received.
+    fn idle(&mut self) {
+        // NOTE: the other transports already waited in recv.
+        if let Transport::Busy(_) = self {
+            std::hint::spin_loop();
         }
     }
 
-    pub fn exec_function_by_name(&mut self, name: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let instance = self.instance.as_ref().unwrap();
-        let store = &mut self.store;
-
-        let func = instance.exports.get_function(name)?;
-
-        let memory = instance.exports.get_memory("memory")?;
-
-        let memory_view = memory.view(store);
-        let memory_view_size = memory_view.size();
-
-        let max_offset = memory_view_size.bytes().0 / 8;
-        memory.grow(store, 1).unwrap();
-
-        let memory_view = memory.view(store);
-
-        let data_offset = max_offset;
-        memory_view.write(data_offset as u64, data)?;
-
-        let out_offset = data_offset + data.len();
-
-        let res = func.call(
-            store,
-            &[
-                Value::I32(data_offset as i32),
-                Value::I32(data.len() as i32),
-                Value::I32(out_offset as i32),
-            ],
-        )?;
-
-        log::debug!("invoked function: {}, with result: {:?}", name, res);
-
-        let result = res[0].unwrap_i32();
-        let mut buf = vec![0u8; result as usize];
-        memory_view.read(out_offset as u64, &mut buf)?;
-
-        Ok(buf)
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Busy(_) | Transport::Event { .. } => Ok(()),
+            #[cfg(feature = "uring")]
+            Transport::Uring { uring, .. } => uring.flush(),
+        }
     }
 }
 
-fn main_loop(r: Arc<AtomicBool>, sock: net::UdpSocket, args: &Args, addr: SocketAddr) {
-    let mut initialized = false;
-    let mut buf_send: Vec<u8> = Vec::with_capacity(2048);
+fn recv_into(sock: &TemposSocket, buf: &mut Buffer) -> io::Result<Option<PeerAddr>> {
+    buf.clear();
+    let (size, from) = sock.recv_from(buf.spare_mut())?;
+    buf.commit(size);
+    Ok(from)
+}
 
-    // NOTE(garbu):
-    // function map where key is topic name and value is tuple of (function name, next topic name)
-    // if next topic name is empty, then it means that this is the last function in the chain or that
-    // the function has a side effect and does not return any data
-    let mut functions_map = HashMap::new();
-    functions_map.insert("vpn", ("comp", "enc"));
-    functions_map.insert("enc", ("encrypt", "dec"));
-    functions_map.insert("dec", ("decrypt", "dcp"));
-    functions_map.insert("dcp", ("decomp", "out"));
-    functions_map.insert("out", ("time", ""));
+/// Restricts the calling thread to `cpu`.
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    // NOTE: CPU_SET panics past the end of the set.
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "CPU {} is out of the {} CPUs of a set",
+                cpu,
+                libc::CPU_SETSIZE
+            ),
+        ));
+    }
 
-    let mut invoker = WASMInvoker::new();
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
 
-    let mut trace_interval = Instant::now();
+fn main_loop(
+    r: Arc<AtomicBool>,
+    sock: TemposSocket,
+    ring: Option<ShmRing>,
+    mut chains: ChainRegistry,
+    args: &Args,
+    addr: PeerAddr,
+    state: Arc<InvokerState>,
+) {
+    // NOTE: messages are received into and forwarded from preallocated
+    //       buffers, the loop does not allocate per message.
+    let pool = BufferPool::new(2, MAX_MESSAGE_LEN, 0);
+    let mut buf_recv = pool.get().unwrap();
+    let mut buf_send = pool.get().unwrap();
+
+    if let Some(cpu) = args.cpu {
+        match pin_to_cpu(cpu) {
+            Ok(()) => log::info!("main loop pinned to CPU {}", cpu),
+            Err(e) => log::error!("unable to pin the main loop to CPU {}: {}", cpu, e),
+        }
+    }
 
-    let mut buf_recv = [0u8; 2048];
+    // NOTE: results go to a UDP MOM with launch times when the interface has
+    //       an etf/taprio qdisc, through the receive socket otherwise.
+    let txtime = match (&args.txtime_iface, &addr) {
+        (Some(iface), PeerAddr::Udp(_)) => {
+            let scheduler = TxTimeScheduler::new(
+                args.txtime_cycle_ns,
+                args.txtime_offset_ns,
+                args.txtime_window_ns,
+                args.txtime_guard_ns,
+            )
+            .map(|scheduler| scheduler.with_base_time(args.txtime_base_ns));
+            let clock = tempos::txtime::clock_from_name(&args.txtime_clock);
+            match scheduler.and_then(|scheduler| {
+                TxTimeSocket::open(args.txtime_priority, iface, clock?, scheduler)
+            }) {
+                Ok(sock) => {
+                    log::info!("forwarding with SO_TXTIME through {}", iface);
+                    Some(sock)
+                }
+                Err(e) => {
+                    log::warn!(
+                        "SO_TXTIME unavailable on {}, forwarding without launch times: {}",
+                        iface,
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        (Some(_), _) => {
+            log::warn!("txtime only applies to a UDP MOM, {} ignores it", addr);
+            None
+        }
+        (None, _) => None,
+    };
+
+    let mut invoker = WASMInvoker::new(args.module_budget_mb << 20, args.instance_memory_mb << 20);
+    let mut transport = match Transport::new(sock, ring.as_ref(), args.busy_poll) {
+        Ok(transport) => transport,
+        Err(e) => {
+            log::error!("unable to set up the receive loop: {}", e);
+            r.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    let mut dropped: u64 = 0;
+    let mut expired: u64 = 0;
+    let mut unknown: u64 = 0;
+    let mut last_invocation = Instant::now();
+    let mut chains_checked = Instant::now();
+    let mut txtime_errors = TxTimeErrors::default();
+    let mut txtime_checked = Instant::now();
     log::debug!("starting main loop");
     println!("id,func,ts_start,ts_end");
-    while r.load(std::sync::atomic::Ordering::Relaxed) {
-        match sock.recv_from(&mut buf_recv) {
-            Ok((size, _)) => {
+    while r.load(Ordering::Relaxed) {
+        if let Some(txtime) = txtime
+            .as_ref()
+            .filter(|_| txtime_checked.elapsed() >= TXTIME_ERRORS_INTERVAL)
+        {
+            txtime_checked = Instant::now();
+            match txtime.errors() {
+                Ok(errors) if errors.is_empty() => {}
+                Ok(errors) => {
+                    txtime_errors.missed += errors.missed;
+                    txtime_errors.invalid += errors.invalid;
+                    log::warn!(
+                        "{} results dropped by the qdisc past their launch time, {} rejected [{} missed, {} invalid]",
+                        errors.missed,
+                        errors.invalid,
+                        txtime_errors.missed,
+                        txtime_errors.invalid
+                    );
+                }
+                Err(e) => log::error!("unable to read the txtime errors: {}", e),
+            }
+        }
+
+        if chains_checked.elapsed() >= CHAINS_RELOAD_INTERVAL {
+            chains_checked = Instant::now();
+            match chains.reload_if_changed() {
+                Ok(true) => log::info!("{} functions reloaded from {}", chains.len(), args.chains),
+                Ok(false) => {}
+                Err(e) => log::error!("keeping the current chains: {:#}", e),
+            }
+        }
+
+        // NOTE: the ring is polled first, the socket is the fallback used by
+        //       remote MOMs and when the ring is full. A message from the
+        //       ring is read in place, its slot is released at the end of
+        //       the iteration.
+        let slot = ring.as_ref().and_then(ShmRing::next);
+        let received = match &slot {
+            Some(slot) => Ok((&slot[..], None)),
+            None => transport
+                .recv(&mut buf_recv, ring.as_ref())
+                .map(|from| (buf_recv.as_slice(), from)),
+        };
+
+        match received {
+            Ok((received, _)) => {
                 let start_ns = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
                     .unwrap()
                     .as_nanos();
-                let mut read_ptr = 0;
-                let msg_type = u8::from_be_bytes([buf_recv[read_ptr]]);
-                read_ptr += 1;
-                match msg_type {
-                    tempos::msg_type::INVOK => {
-                        let msg_seq = u32::from_be_bytes([
-                            buf_recv[read_ptr],
-                            buf_recv[read_ptr + 1],
-                            buf_recv[read_ptr + 2],
-                            buf_recv[read_ptr + 3],
-                        ]);
-
-                        read_ptr += 4;
-                        let topic_len = u32::from_be_bytes([
-                            buf_recv[read_ptr],
-                            buf_recv[read_ptr + 1],
-                            buf_recv[read_ptr + 2],
-                            buf_recv[read_ptr + 3],
-                        ]);
-                        read_ptr += 4;
-
-                        let topic =
-                            std::str::from_utf8(&buf_recv[read_ptr..read_ptr + topic_len as usize])
-                                .unwrap();
-                        read_ptr += topic_len as usize;
-
-                        log::debug!("invoking topic: {}", topic);
-
-                        if !args.warm || (args.warm && !initialized) {
-                            invoker.load("final.so").unwrap();
-                            initialized = true;
+                let (header, msg) = match decode(received) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        dropped += 1;
+                        log::warn!(
+                            "dropping malformed message ({} bytes): {} [{} dropped]",
+                            received.len(),
+                            e,
+                            dropped
+                        );
+                        continue;
+                    }
+                };
+                match msg {
+                    TemposMessage::Invok {
+                        seq: msg_seq,
+                        chain,
+                        hop,
+                        topic,
+                        data,
+                    } => {
+                        log::debug!("invoking topic: {} (chain {}, hop {})", topic, chain, hop);
+                        last_invocation = Instant::now();
+
+                        if header.is_expired(start_ns as u64) {
+                            expired += 1;
+                            log::warn!(
+                                "skipping message {} for topic '{}', deadline missed [{} expired]",
+                                msg_seq,
+                                topic,
+                                expired
+                            );
+                            continue;
                         }
 
-                        let (function_name, out_topic) = functions_map.get(topic).unwrap();
-                        let data_len = u32::from_be_bytes([
-                            buf_recv[read_ptr],
-                            buf_recv[read_ptr + 1],
-                            buf_recv[read_ptr + 2],
-                            buf_recv[read_ptr + 3],
-                        ]);
-
-                        read_ptr += 4;
-
-                        let data = &buf_recv[read_ptr..read_ptr + data_len as usize];
+                        let function = match chains.get(topic) {
+                            Some(function) => function,
+                            None => {
+                                unknown += 1;
+                                log::warn!(
+                                    "dropping message {} for unknown topic '{}' [{} unknown]",
+                                    msg_seq,
+                                    topic,
+                                    unknown
+                                );
+                                report_failure(
+                                    &mut transport,
+                                    &addr,
+                                    &mut buf_send,
+                                    &header,
+                                    &msg,
+                                    &InvocationError::UnknownTopic(topic.to_string()),
+                                );
+                                continue;
+                            }
+                        };
+                        let function_name = function.export.as_str();
+
+                        if function_name.eq_ignore_ascii_case(TIME_EXPORT) {
+                            // NOTE: the header keeps the timestamp of the first hop, so this
+                            //       row carries the end-to-end latency of the whole chain.
+                            let now_ns = tempos::now_ns();
+                            println!(
+                                "{},{},{},{}",
+                                msg_seq, function_name, header.timestamp, now_ns
+                            );
+                            log::debug!(
+                                "chain for message {} completed in {} ns",
+                                msg_seq,
+                                header.latency_ns(now_ns)
+                            );
+                            continue;
+                        }
 
-                        if function_name.eq_ignore_ascii_case("time") {
-                            let now_ns = std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_nanos();
-                            println!("{},{},0,{}", msg_seq, function_name, now_ns);
+                        // NOTE: only the time export has no module, handled above.
+                        let module = match &function.module {
+                            Some(module) => module,
+                            None => continue,
+                        };
+                        // NOTE: without --warm every invocation is a cold start.
+                        if !args.warm {
+                            invoker.evict(module);
+                        }
+                        if let Err(e) = invoker.prepare(module) {
+                            log::error!("unable to load module {}: {}", module, e);
+                            report_failure(&mut transport, &addr, &mut buf_send, &header, &msg, &e);
                             continue;
                         }
+                        state.warm.store(true, Ordering::Relaxed);
 
-                        let data_str = String::from_utf8(data.to_vec())
-                            .unwrap_or("Unable to convert to string".to_string());
+                        let data_str =
+                            std::str::from_utf8(data).unwrap_or("Unable to convert to string");
                         log::debug!(
                             "invoking function: {}, with data {:?}",
                             function_name,
                             data_str
                         );
 
-                        if let Ok(output) = invoker.exec_function_by_name(function_name, data) {
-                            if let Ok(output_str) = String::from_utf8(output.to_vec()) {
-                                log::debug!("output: {:?}", output_str);
-                            } else {
-                                log::debug!("output: {:?}", output);
+                        state.in_flight.fetch_add(1, Ordering::Relaxed);
+                        let result = invoker.exec_function(module, function_name, data);
+                        state.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+                        let output = match result {
+                            Ok(output) => output,
+                            Err(e) => {
+                                log::error!(
+                                    "message {}: function {} failed: {}",
+                                    msg_seq,
+                                    function_name,
+                                    e
+                                );
+                                report_failure(
+                                    &mut transport,
+                                    &addr,
+                                    &mut buf_send,
+                                    &header,
+                                    &msg,
+                                    &e,
+                                );
+                                continue;
                             }
+                        };
 
-                            if !out_topic.is_empty() {
-                                buf_send.clear();
-                                buf_send
-                                    .write(&tempos::msg_type::INVOK.to_be_bytes())
-                                    .unwrap();
-                                buf_send.write(&msg_seq.to_be_bytes()).unwrap();
-
-                                let out_topic_len = out_topic.len() as u32;
-                                buf_send.write(&out_topic_len.to_be_bytes()).unwrap();
-                                buf_send.write(&out_topic.as_bytes()).unwrap();
-
-                                let output_len = output.len() as u32;
-                                buf_send.write(&output_len.to_be_bytes()).unwrap();
-                                buf_send.write(&output).unwrap();
-
-                                log::trace!("sending message: {:?}", &buf_send);
-
-                                if args.test == 1 {
-                                    let end_ns = std::time::SystemTime::now()
-                                        .duration_since(std::time::UNIX_EPOCH)
-                                        .unwrap()
-                                        .as_nanos();
-                                    println!(
-                                        "{},{},{},{}",
-                                        msg_seq, function_name, start_ns, end_ns
-                                    );
-                                }
-
-                                sock.send_to(&buf_send, addr).unwrap();
-                            }
+                        if let Ok(output_str) = std::str::from_utf8(&output) {
+                            log::debug!("output: {:?}", output_str);
+                        } else {
+                            log::debug!("output: {:?}", output);
+                        }
 
-                            continue;
+                        // NOTE: the output of a chain hop goes back to the MOM, which
+                        //       resolves the topic of the next one. Outside of a
+                        //       chain the manifest tells where it goes.
+                        let chained = (chain != 0).then(|| (chain, hop.saturating_add(1), ""));
+                        let unchained = function
+                            .next
+                            .iter()
+                            .filter(|_| chain == 0)
+                            .map(|topic| (0, 0, topic.as_str()));
+                        let mut forwards = chained.into_iter().chain(unchained).peekable();
+
+                        if args.test == 1 && forwards.peek().is_some() {
+                            let end_ns = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .unwrap()
+                                .as_nanos();
+                            println!("{},{},{},{}", msg_seq, function_name, start_ns, end_ns);
                         }
 
-                        log::error!("failed to invoke function: {}", function_name);
+                        for (chain, hop, out_topic) in forwards {
+                            buf_send.clear();
+                            let encoded = TemposMessage::Invok {
+                                seq: msg_seq,
+                                chain,
+                                hop,
+                                topic: out_topic,
+                                data: &output,
+                            }
+                            .encode_into(&header, &mut buf_send);
+                            if let Err(e) = encoded {
+                                log::error!(
+                                    "unable to forward message {} to '{}': {}",
+                                    msg_seq,
+                                    out_topic,
+                                    e
+                                );
+                                let error = InvocationError::Io(format!(
+                                    "output of {} bytes exceeds the {} bytes of an INVOK for '{}'",
+                                    output.len(),
+                                    max_invok_data_len(out_topic),
+                                    out_topic
+                                ));
+                                report_failure(
+                                    &mut transport,
+                                    &addr,
+                                    &mut buf_send,
+                                    &header,
+                                    &msg,
+                                    &error,
+                                );
+                                continue;
+                            }
+
+                            log::trace!("sending message: {:?}", buf_send.as_slice());
+
+                            if let (Some(txtime), PeerAddr::Udp(mom)) = (&txtime, &addr) {
+                                match txtime.send_to(buf_send.as_slice(), *mom, header.deadline)
+                                    {
+                                        Ok(_) => continue,
+                                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                                            expired += 1;
+                                            log::warn!(
+                                                "skipping forward of message {}: {} [{} expired]",
+                                                msg_seq,
+                                                e,
+                                                expired
+                                            );
+                                            continue;
+                                        }
+                                        Err(e) => log::warn!(
+                                            "SO_TXTIME forward of message {} failed, sending without launch time: {}",
+                                            msg_seq,
+                                            e
+                                        ),
+                                    }
+                            }
+
+                            if let Err(e) = transport.send_to(buf_send.as_slice(), &addr) {
+                                log::error!("failed to forward message {}: {}", msg_seq, e);
+                            }
+                        }
                     }
                     _ => log::debug!("Unhandled message type"),
                 }
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                if initialized {
-                    invoker.unload();
-                    initialized = false;
-                    log::debug!("Unloading WASM module due to timeout");
+                if invoker.is_warm() && last_invocation.elapsed() >= COOL_DOWN_AFTER {
+                    invoker.cool_down();
+                    state.warm.store(false, Ordering::Relaxed);
+                    log::debug!(
+                        "Dropping the WASM instances due to timeout, {} bytes cached",
+                        invoker.used()
+                    );
                 }
 
-                std::thread::sleep(std::time::Duration::from_micros(10));
+                transport.idle();
             }
             Err(e) => {
                 println!("encountered IO error: {}", e);
@@ -379,10 +847,10 @@ fn main_loop(r: Arc<AtomicBool>, sock: net::UdpSocket, args: &Args, addr: Socket
     }
 
     buf_send.clear();
-    buf_send
-        .write(&tempos::msg_type::UNREGISTRATION.to_be_bytes())
+    TemposMessage::Unregistration { node_id: args.node }
+        .encode_into(&TemposHeader::now(), &mut buf_send)
         .unwrap();
-    buf_send.write(&args.node.to_be_bytes()).unwrap();
 
-    sock.send_to(&buf_send, addr).unwrap();
+    transport.send_to(buf_send.as_slice(), &addr).unwrap();
+    transport.flush().unwrap();
 }
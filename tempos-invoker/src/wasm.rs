@@ -0,0 +1,569 @@
+use anyhow::{anyhow, bail, Context};
+use std::{collections::HashMap, fmt, fs};
+use tempos::failure;
+use tempos::message::max_invok_data_len;
+use wasmer::{
+    imports, Engine, Instance, Memory, MemoryView, Module, RuntimeError, Store, TypedFunction,
+};
+
+/// Size of a page of linear memory.
+const PAGE_SIZE: usize = 0x10000;
+
+/// Largest input and output of a function, they travel in a single message.
+/// An output this long only fits a chained INVOK, which has no topic.
+pub const MAX_IO_LEN: usize = max_invok_data_len("");
+
+/// Signature of the functions of a module: `(in_ptr, in_len, out_ptr) ->
+/// status`, the output buffer holding at least `MAX_IO_LEN` bytes. A status
+/// of 0 or more is the length of the output, a negative one is a failure
+/// reported as is to the sender of the INVOK.
+type GuestFunction = TypedFunction<(i32, i32, i32), i32>;
+
+/// Why an invocation failed.
+#[derive(Debug)]
+pub enum InvocationError {
+    /// The module could not be loaded or instantiated.
+    Module(anyhow::Error),
+    /// The module does not export the function with the expected signature.
+    NoFunction(String),
+    /// The input or output does not fit the memory of the instance or the
+    /// message carrying it.
+    Io(String),
+    /// The function trapped.
+    Trap(RuntimeError),
+    /// The function returned a negative status.
+    Status(i32),
+    /// The invoker serves no function for the topic.
+    UnknownTopic(String),
+}
+
+impl InvocationError {
+    /// One of the `failure` constants.
+    pub fn kind(&self) -> u8 {
+        match self {
+            InvocationError::Module(_) => failure::MODULE,
+            InvocationError::NoFunction(_) => failure::NO_FUNCTION,
+            InvocationError::Io(_) => failure::IO,
+            InvocationError::Trap(_) => failure::TRAP,
+            InvocationError::Status(_) => failure::STATUS,
+            InvocationError::UnknownTopic(_) => failure::UNKNOWN_TOPIC,
+        }
+    }
+
+    /// Status returned by the function, 0 if it did not return.
+    pub fn status(&self) -> i32 {
+        match self {
+            InvocationError::Status(status) => *status,
+            _ => 0,
+        }
+    }
+
+    /// Whether the instance may be left in any state and must be dropped.
+    fn poisons_instance(&self) -> bool {
+        matches!(self, InvocationError::Io(_) | InvocationError::Trap(_))
+    }
+}
+
+impl fmt::Display for InvocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvocationError::Module(e) => write!(f, "{:#}", e),
+            InvocationError::NoFunction(name) => write!(f, "no function {}", name),
+            InvocationError::Io(reason) => write!(f, "{}", reason),
+            InvocationError::Trap(e) => write!(f, "trapped: {}", e),
+            InvocationError::Status(status) => write!(f, "returned status {}", status),
+            InvocationError::UnknownTopic(topic) => write!(f, "no function for topic '{}'", topic),
+        }
+    }
+}
+
+impl std::error::Error for InvocationError {}
+
+/// A compiled module, with an instance while it is warm.
+struct CachedModule {
+    module: Module,
+    /// Size of the compiled artifact.
+    artifact_bytes: usize,
+    instance: Option<LiveInstance>,
+    last_used: u64,
+}
+
+/// An instance in a store of its own: the store owns the linear memory,
+/// which is only released when the store is dropped.
+struct LiveInstance {
+    store: Store,
+    instance: Instance,
+    memory: Memory,
+    io: GuestIo,
+    memory_bytes: usize,
+}
+
+/// Where the input and output of a call are placed in the linear memory.
+enum GuestIo {
+    /// The module exports `alloc(len) -> ptr` and `dealloc(ptr, len)`, the
+    /// buffers are allocated by the guest for each call.
+    Alloc {
+        alloc: TypedFunction<i32, i32>,
+        dealloc: TypedFunction<(i32, i32), ()>,
+    },
+    /// Pages grown by the host once per instance: the input at `offset`,
+    /// the output right after it. The guest allocator never hands out pages
+    /// it did not grow itself, so they are reused by every call.
+    Arena { offset: usize },
+}
+
+impl CachedModule {
+    fn bytes(&self) -> usize {
+        self.artifact_bytes + self.instance.as_ref().map_or(0, |live| live.memory_bytes)
+    }
+}
+
+/// Hosts the WASM modules of the invoker, keyed by path.
+///
+/// Compiled modules and their instances stay cached until the memory they
+/// use exceeds the budget. The instances of the least recently used modules
+/// are dropped first, being cheaper to rebuild, then the modules themselves.
+pub struct WASMInvoker {
+    engine: Engine,
+    modules: HashMap<String, CachedModule>,
+    /// Bytes the cached artifacts and linear memories may use.
+    budget: usize,
+    /// Bytes the linear memory of an instance may grow to before it is
+    /// dropped and instantiated again.
+    memory_cap: usize,
+    /// Incremented on every use, orders the modules for eviction.
+    clock: u64,
+}
+
+impl WASMInvoker {
+    pub fn new(budget: usize, memory_cap: usize) -> Self {
+        WASMInvoker {
+            engine: Engine::headless(),
+            modules: HashMap::new(),
+            budget,
+            memory_cap,
+            clock: 0,
+        }
+    }
+
+    /// Memory used by the cached modules and their instances.
+    pub fn used(&self) -> usize {
+        self.modules.values().map(CachedModule::bytes).sum()
+    }
+
+    /// Whether an instance is ready to be invoked.
+    pub fn is_warm(&self) -> bool {
+        self.modules
+            .values()
+            .any(|cached| cached.instance.is_some())
+    }
+
+    /// Makes `name` ready to be invoked, compiling and instantiating it if it
+    /// is not cached. Returns whether it had to be instantiated.
+    pub fn prepare(&mut self, name: &str) -> Result<bool, InvocationError> {
+        self.load(name).map_err(InvocationError::Module)
+    }
+
+    fn load(&mut self, name: &str) -> anyhow::Result<bool> {
+        self.clock += 1;
+
+        if !self.modules.contains_key(name) {
+            let artifact_bytes = fs::metadata(name)
+                .with_context(|| format!("unable to open module {}", name))?
+                .len() as usize;
+            self.make_room(name, artifact_bytes)?;
+
+            log::debug!("loading module: {}", name);
+            let store = Store::new(&self.engine);
+            let module = unsafe { Module::deserialize_from_file(&store, name)? };
+            self.modules.insert(
+                name.to_string(),
+                CachedModule {
+                    module,
+                    artifact_bytes,
+                    instance: None,
+                    last_used: self.clock,
+                },
+            );
+        }
+
+        let cached = self.modules.get_mut(name).unwrap();
+        cached.last_used = self.clock;
+        if cached.instance.is_some() {
+            return Ok(false);
+        }
+
+        log::debug!("instantiating module: {}", name);
+        let live = instantiate(&self.engine, &cached.module)
+            .with_context(|| format!("unable to instantiate module {}", name))?;
+        if live.memory_bytes > self.memory_cap {
+            bail!(
+                "module {} needs {} bytes of memory, more than the {} bytes cap",
+                name,
+                live.memory_bytes,
+                self.memory_cap
+            );
+        }
+        cached.instance = Some(live);
+
+        // NOTE: the memory of an instance is only known once it exists.
+        if let Err(e) = self.make_room(name, 0) {
+            self.modules.remove(name);
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+
+    /// Drops `name` and its instance from the cache.
+    pub fn evict(&mut self, name: &str) {
+        if self.modules.remove(name).is_some() {
+            log::debug!("evicted module: {}", name);
+        }
+    }
+
+    /// Drops every instance, the compiled modules stay cached.
+    pub fn cool_down(&mut self) {
+        for cached in self.modules.values_mut() {
+            cached.instance = None;
+        }
+    }
+
+    /// Drops the least recently used instances, then modules, other than
+    /// `keep` until `extra` more bytes fit in the budget.
+    fn make_room(&mut self, keep: &str, extra: usize) -> anyhow::Result<()> {
+        while self.used() + extra > self.budget {
+            let lru = |with_instance: bool| {
+                self.modules
+                    .iter()
+                    .filter(|(name, cached)| {
+                        name.as_str() != keep && (!with_instance || cached.instance.is_some())
+                    })
+                    .min_by_key(|(_, cached)| cached.last_used)
+                    .map(|(name, _)| name.clone())
+            };
+
+            if let Some(name) = lru(true) {
+                log::debug!(
+                    "dropping the instance of module {} to stay within budget",
+                    name
+                );
+                self.modules.get_mut(&name).unwrap().instance = None;
+            } else if let Some(name) = lru(false) {
+                log::debug!("evicting module {} to stay within budget", name);
+                self.modules.remove(&name);
+            } else {
+                bail!(
+                    "module {} needs {} bytes, more than the {} bytes budget",
+                    keep,
+                    self.modules.get(keep).map_or(0, CachedModule::bytes) + extra,
+                    self.budget
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calls the export `name` of `module`, which must have been prepared.
+    pub fn exec_function(
+        &mut self,
+        module: &str,
+        name: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, InvocationError> {
+        self.clock += 1;
+        let cached = self
+            .modules
+            .get_mut(module)
+            .ok_or_else(|| InvocationError::Module(anyhow!("module {} is not loaded", module)))?;
+        cached.last_used = self.clock;
+        let live = cached.instance.as_mut().ok_or_else(|| {
+            InvocationError::Module(anyhow!("module {} is not instantiated", module))
+        })?;
+
+        let result = live.call(name, data);
+        live.memory_bytes = live.memory.view(&live.store).data_size() as usize;
+
+        // NOTE: a trap may have left the instance in any state, and memory
+        //       grown past the cap is only released with the store.
+        let poisoned = matches!(&result, Err(e) if e.poisons_instance());
+        if poisoned || live.memory_bytes > self.memory_cap {
+            log::debug!(
+                "dropping the instance of module {} with {} bytes of memory",
+                module,
+                live.memory_bytes
+            );
+            cached.instance = None;
+        }
+
+        // NOTE: the call may have grown the memory past the budget, the
+        //       other modules make room for it.
+        self.make_room(module, 0).map_err(InvocationError::Module)?;
+
+        result
+    }
+}
+
+impl LiveInstance {
+    fn call(&mut self, name: &str, data: &[u8]) -> Result<Vec<u8>, InvocationError> {
+        if data.len() > MAX_IO_LEN {
+            return Err(InvocationError::Io(format!(
+                "input of {} bytes exceeds {} bytes",
+                data.len(),
+                MAX_IO_LEN
+            )));
+        }
+
+        let func: GuestFunction = self
+            .instance
+            .exports
+            .get_typed_function(&self.store, name)
+            .map_err(|_| InvocationError::NoFunction(name.to_string()))?;
+
+        let (alloc, dealloc) = match &self.io {
+            GuestIo::Arena { offset } => {
+                let offset = *offset;
+                return self.call_at(&func, name, data, offset, offset + MAX_IO_LEN);
+            }
+            GuestIo::Alloc { alloc, dealloc } => (alloc.clone(), dealloc.clone()),
+        };
+
+        // NOTE: the buffers are not freed when the instance is dropped
+        //       anyway, see `InvocationError::poisons_instance`.
+        let in_ptr = alloc
+            .call(&mut self.store, data.len() as i32)
+            .map_err(InvocationError::Trap)?;
+        let out_ptr = alloc
+            .call(&mut self.store, MAX_IO_LEN as i32)
+            .map_err(InvocationError::Trap)?;
+        if in_ptr == 0 || out_ptr == 0 {
+            return Err(InvocationError::Io(
+                "alloc returned a null pointer".to_string(),
+            ));
+        }
+
+        let output = self.call_at(
+            &func,
+            name,
+            data,
+            in_ptr as u32 as usize,
+            out_ptr as u32 as usize,
+        );
+        if matches!(&output, Err(e) if e.poisons_instance()) {
+            return output;
+        }
+        dealloc
+            .call(&mut self.store, out_ptr, MAX_IO_LEN as i32)
+            .map_err(InvocationError::Trap)?;
+        dealloc
+            .call(&mut self.store, in_ptr, data.len() as i32)
+            .map_err(InvocationError::Trap)?;
+
+        output
+    }
+
+    /// Calls `func` with the input at `in_ptr` and the output at `out_ptr`.
+    fn call_at(
+        &mut self,
+        func: &GuestFunction,
+        name: &str,
+        data: &[u8],
+        in_ptr: usize,
+        out_ptr: usize,
+    ) -> Result<Vec<u8>, InvocationError> {
+        let view = self.memory.view(&self.store);
+        check_bounds(&view, "input", in_ptr, data.len())?;
+        check_bounds(&view, "output", out_ptr, MAX_IO_LEN)?;
+        view.write(in_ptr as u64, data)
+            .map_err(|e| InvocationError::Io(format!("unable to write the input: {}", e)))?;
+
+        let res = func
+            .call(
+                &mut self.store,
+                in_ptr as i32,
+                data.len() as i32,
+                out_ptr as i32,
+            )
+            .map_err(InvocationError::Trap)?;
+
+        log::debug!("invoked function: {}, with result: {:?}", name, res);
+
+        let len = match usize::try_from(res) {
+            Ok(len) if len <= MAX_IO_LEN => len,
+            Ok(len) => {
+                return Err(InvocationError::Io(format!(
+                    "output of {} bytes exceeds {} bytes",
+                    len, MAX_IO_LEN
+                )))
+            }
+            Err(_) => return Err(InvocationError::Status(res)),
+        };
+
+        // NOTE: the call may have grown the memory, the view is taken again.
+        let view = self.memory.view(&self.store);
+        let mut buf = vec![0u8; len];
+        view.read(out_ptr as u64, &mut buf)
+            .map_err(|e| InvocationError::Io(format!("unable to read the output: {}", e)))?;
+
+        Ok(buf)
+    }
+}
+
+/// Instantiates `module` in a new store and sets up its I/O buffers.
+fn instantiate(engine: &Engine, module: &Module) -> anyhow::Result<LiveInstance> {
+    let mut store = Store::new(engine);
+    let instance = Instance::new(&mut store, module, &imports! {})?;
+    let memory = instance
+        .exports
+        .get_memory("memory")
+        .context("no exported memory")?
+        .clone();
+
+    let alloc = instance.exports.get_typed_function(&store, "alloc");
+    let dealloc = instance.exports.get_typed_function(&store, "dealloc");
+    let io = match (alloc, dealloc) {
+        (Ok(alloc), Ok(dealloc)) => GuestIo::Alloc { alloc, dealloc },
+        _ => {
+            let pages = (2 * MAX_IO_LEN).div_ceil(PAGE_SIZE);
+            let previous = memory
+                .grow(&mut store, pages as u32)
+                .context("unable to grow the memory for the I/O arena")?;
+            GuestIo::Arena {
+                offset: previous.bytes().0,
+            }
+        }
+    };
+    let memory_bytes = memory.view(&store).data_size() as usize;
+
+    Ok(LiveInstance {
+        store,
+        instance,
+        memory,
+        io,
+        memory_bytes,
+    })
+}
+
+/// Fails unless the `len` bytes of the `what` buffer at `ptr` are within the
+/// memory of `view`.
+fn check_bounds(
+    view: &MemoryView,
+    what: &str,
+    ptr: usize,
+    len: usize,
+) -> Result<(), InvocationError> {
+    match ptr.checked_add(len) {
+        Some(end) if end as u64 <= view.data_size() => Ok(()),
+        _ => Err(InvocationError::Io(format!(
+            "{} buffer of {} bytes at {:#x} is out of the {} bytes of memory",
+            what,
+            len,
+            ptr,
+            view.data_size()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Functions over the host arena: `echo` copies its input to its output,
+    /// the others return their status without touching the memory.
+    fn arena_wat() -> String {
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "echo") (param i32 i32 i32) (result i32)
+                    (local $i i32)
+                    (block $done
+                        (loop $copy
+                            (br_if $done (i32.ge_u (local.get $i) (local.get 1)))
+                            (i32.store8
+                                (i32.add (local.get 2) (local.get $i))
+                                (i32.load8_u (i32.add (local.get 0) (local.get $i))))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $copy)))
+                    (local.get 1))
+                (func (export "failed") (param i32 i32 i32) (result i32)
+                    (i32.const -7))
+                (func (export "oversized") (param i32 i32 i32) (result i32)
+                    (i32.const {}))
+                (func (export "trap") (param i32 i32 i32) (result i32)
+                    (unreachable)))"#,
+            MAX_IO_LEN + 1
+        )
+    }
+
+    /// A guest allocator handing out buffers past the end of its memory.
+    const OUT_OF_RANGE_WAT: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32)
+            (i32.const 0x7fff0000))
+        (func (export "dealloc") (param i32 i32))
+        (func (export "echo") (param i32 i32 i32) (result i32)
+            (local.get 1)))"#;
+
+    fn live(wat: &str) -> LiveInstance {
+        let store = Store::default();
+        let module = Module::new(&store, wat).unwrap();
+        instantiate(store.engine(), &module).unwrap()
+    }
+
+    #[test]
+    fn arena_echo() {
+        let mut live = live(&arena_wat());
+        assert!(matches!(live.io, GuestIo::Arena { .. }));
+
+        assert_eq!(live.call("echo", b"in").unwrap(), b"in");
+        let full = vec![0xa5; MAX_IO_LEN];
+        assert_eq!(live.call("echo", &full).unwrap(), full);
+    }
+
+    #[test]
+    fn negative_status() {
+        let err = live(&arena_wat()).call("failed", b"in").unwrap_err();
+        assert_eq!(err.kind(), failure::STATUS);
+        assert_eq!(err.status(), -7);
+        assert!(!err.poisons_instance());
+    }
+
+    #[test]
+    fn output_cap() {
+        let err = live(&arena_wat()).call("oversized", b"in").unwrap_err();
+        assert_eq!(err.kind(), failure::IO);
+        assert_eq!(err.status(), 0);
+        assert!(err.poisons_instance());
+    }
+
+    #[test]
+    fn input_cap() {
+        let err = live(&arena_wat())
+            .call("echo", &vec![0; MAX_IO_LEN + 1])
+            .unwrap_err();
+        assert_eq!(err.kind(), failure::IO);
+    }
+
+    #[test]
+    fn out_of_range_buffers() {
+        let mut live = live(OUT_OF_RANGE_WAT);
+        assert!(matches!(live.io, GuestIo::Alloc { .. }));
+
+        let err = live.call("echo", b"in").unwrap_err();
+        assert_eq!(err.kind(), failure::IO);
+        assert!(err.to_string().contains("out of the"), "{}", err);
+    }
+
+    #[test]
+    fn trap_and_missing_function() {
+        let mut live = live(&arena_wat());
+
+        let err = live.call("trap", b"in").unwrap_err();
+        assert_eq!(err.kind(), failure::TRAP);
+        assert!(err.poisons_instance());
+
+        let err = live.call("missing", b"in").unwrap_err();
+        assert_eq!(err.kind(), failure::NO_FUNCTION);
+    }
+}
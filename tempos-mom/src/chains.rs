@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::config::ChainConfig;
+
+/// Where an INVOK goes according to its chain id and hop index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route<'a> {
+    /// Not part of a chain, forwarded to its own topic.
+    Topic,
+    /// Hop `hop` of `chain`, served by `topic`.
+    Hop { chain: u16, hop: u8, topic: &'a str },
+    /// Output of the last hop of `chain`.
+    Completed { chain: u16, name: &'a str },
+    /// `chain` is not configured.
+    Unknown { chain: u16 },
+}
+
+/// The service function chains of the MOM.
+///
+/// An INVOK without chain for the first topic of a chain starts it at hop
+/// 0. Invokers answer hop `n` with an INVOK for hop `n + 1` of the same
+/// chain whose topic is resolved here, so they don't know the chains.
+#[derive(Debug, Default)]
+pub struct Chains {
+    chains: HashMap<u16, ChainConfig>,
+    /// First topic of each chain.
+    entries: HashMap<String, u16>,
+}
+
+impl Chains {
+    /// The configuration must have been validated.
+    pub fn from_config(configs: &[ChainConfig]) -> Self {
+        let mut chains = Self::default();
+        for chain in configs {
+            chains.entries.insert(chain.topics[0].clone(), chain.id);
+            chains.chains.insert(chain.id, chain.clone());
+        }
+
+        chains
+    }
+
+    pub fn len(&self) -> usize {
+        self.chains.len()
+    }
+
+    pub fn route(&self, chain: u16, hop: u8, topic: &str) -> Route<'_> {
+        let (id, hop) = match chain {
+            0 => match self.entries.get(topic) {
+                Some(&id) => (id, 0),
+                None => return Route::Topic,
+            },
+            id => (id, hop),
+        };
+
+        let config = match self.chains.get(&id) {
+            Some(config) => config,
+            None => return Route::Unknown { chain: id },
+        };
+
+        match config.topics.get(hop as usize) {
+            Some(topic) => Route::Hop {
+                chain: id,
+                hop,
+                topic,
+            },
+            None => Route::Completed {
+                chain: id,
+                name: &config.name,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chains() -> Chains {
+        let chain = |id: u16, name: &str, topics: &[&str]| ChainConfig {
+            id,
+            name: name.to_string(),
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        };
+
+        Chains::from_config(&[
+            chain(1, "vpn", &["vpn", "enc", "dec"]),
+            chain(7, "fw", &["fw"]),
+        ])
+    }
+
+    #[test]
+    fn route() {
+        let chains = chains();
+        assert_eq!(chains.len(), 2);
+
+        #[rustfmt::skip]
+        let cases = [
+            // (chain, hop, topic) of the INVOK, expected route
+            ((0, 0, "other"), Route::Topic),
+            ((0, 3, "enc"), Route::Topic),
+            ((0, 0, "vpn"), Route::Hop { chain: 1, hop: 0, topic: "vpn" }),
+            // NOTE: without chain the hop is ignored, the chain starts over.
+            ((0, 5, "vpn"), Route::Hop { chain: 1, hop: 0, topic: "vpn" }),
+            ((0, 0, "fw"), Route::Hop { chain: 7, hop: 0, topic: "fw" }),
+            // NOTE: the topic of a chained INVOK is resolved here.
+            ((1, 1, ""), Route::Hop { chain: 1, hop: 1, topic: "enc" }),
+            ((1, 1, "other"), Route::Hop { chain: 1, hop: 1, topic: "enc" }),
+            ((1, 2, ""), Route::Hop { chain: 1, hop: 2, topic: "dec" }),
+            ((1, 3, ""), Route::Completed { chain: 1, name: "vpn" }),
+            ((1, u8::MAX, ""), Route::Completed { chain: 1, name: "vpn" }),
+            ((7, 1, ""), Route::Completed { chain: 7, name: "fw" }),
+            ((2, 0, "vpn"), Route::Unknown { chain: 2 }),
+            ((u16::MAX, 1, ""), Route::Unknown { chain: u16::MAX }),
+        ];
+
+        for ((chain, hop, topic), route) in cases {
+            assert_eq!(
+                chains.route(chain, hop, topic),
+                route,
+                "chain {} hop {} topic '{}'",
+                chain,
+                hop,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn no_chains() {
+        let chains = Chains::from_config(&[]);
+        assert_eq!(chains.len(), 0);
+        assert_eq!(chains.route(0, 0, "vpn"), Route::Topic);
+        assert_eq!(chains.route(1, 0, "vpn"), Route::Unknown { chain: 1 });
+    }
+}
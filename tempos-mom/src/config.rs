@@ -1,49 +1,607 @@
+use anyhow::{bail, Context};
 use serde::Deserialize;
-use std::{fs::File, io::Read, net::IpAddr};
+use std::{collections::HashSet, fs::File, io::Read};
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct NodeConfig {
-    pub id: String,
-    pub node_type: i32,
-    pub ip: IpAddr,
-    pub k: u32,
-    pub traffic_type: usize,
-    pub topic_type: usize,
+use tempos::endpoint::Endpoint;
+use tempos::txtime::TxTimeScheduler;
+
+use crate::policy::SelectionPolicy;
+use crate::transport::{Registry, Uri};
+
+pub const DEFAULT_SUSPECT_TIMEOUT_MS: u64 = 3000;
+pub const DEFAULT_DEAD_TIMEOUT_MS: u64 = 10000;
+
+/// SO_PRIORITY of the lanes, mapped to traffic classes by config/sched/taprio.json.
+pub const BE_LANE_PRIORITY: i32 = 0;
+pub const STRICT_LANE_PRIORITY: i32 = 3;
+
+/// Environment variables overriding the address of a lane: (variable, lane, priority).
+const LANE_ENV_VARS: [(&str, &str, i32); 2] = [
+    ("BQADDR", "best-effort", BE_LANE_PRIORITY),
+    ("SQADDR", "strict", STRICT_LANE_PRIORITY),
+];
+
+fn default_suspect_timeout_ms() -> u64 {
+    DEFAULT_SUSPECT_TIMEOUT_MS
+}
+
+fn default_dead_timeout_ms() -> u64 {
+    DEFAULT_DEAD_TIMEOUT_MS
 }
 
+/// Messages moved per syscall by a lane, 1 disables batching.
+pub const DEFAULT_LANE_BATCH: usize = 1;
+pub const MAX_LANE_BATCH: usize = 1024;
+
+fn default_lane_batch() -> usize {
+    DEFAULT_LANE_BATCH
+}
+
+fn default_txtime_clock() -> String {
+    "CLOCK_TAI".to_string()
+}
+
+fn default_max_node_usage() -> f64 {
+    1.0
+}
+
+/// A `[[tasks]]` entry, see [`crate::task::MomTask`].
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MomTaskConfig {
     pub id: u8,
+    /// SO_PRIORITY of the egress socket.
     pub priority: i32,
+    /// Transport URI, e.g. `udp://127.0.0.1:3333` or `unix:///run/tempos/in.sock`.
     pub in_endpoint: String,
     pub out_endpoint: String,
+    /// Egress interface the egress socket is bound to. Tasks send without
+    /// launch time, only the lanes schedule with SO_TXTIME.
+    #[serde(default)]
+    pub iface: Option<String>,
 }
 
+/// Most hops in a chain, the hop index is a u8 and the invokers send the
+/// output of the last hop as one more.
+pub const MAX_CHAIN_LEN: usize = u8::MAX as usize;
+
+/// A service function chain: the invocations of its first topic go through
+/// all its topics in order.
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChainConfig {
+    /// Carried by the INVOKs of the chain, 0 is reserved for none.
+    pub id: u16,
+    pub name: String,
+    pub topics: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TopicConfig {
+    pub name: String,
+    pub policy: SelectionPolicy,
+}
+
+/// Launch-time scheduling of the INVOKs forwarded by a lane.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TxTimeConfig {
+    /// Interface with the etf/taprio qdisc.
+    pub iface: String,
+    /// taprio cycle time.
+    pub cycle_ns: u64,
+    /// taprio `base-time`, the start of the first cycle.
+    #[serde(default)]
+    pub base_time_ns: u64,
+    /// Start of the window of the lane priority in the cycle.
+    #[serde(default)]
+    pub offset_ns: u64,
+    /// Length of the window.
+    pub window_ns: u64,
+    /// End of the window in which nothing is launched anymore.
+    #[serde(default)]
+    pub guard_ns: u64,
+    /// `clockid` of the qdisc, the launch times are expressed in it.
+    #[serde(default = "default_txtime_clock")]
+    pub clock: String,
+}
+
+impl TxTimeConfig {
+    pub fn scheduler(&self) -> std::io::Result<TxTimeScheduler> {
+        TxTimeScheduler::new(self.cycle_ns, self.offset_ns, self.window_ns, self.guard_ns)
+            .map(|scheduler| scheduler.with_base_time(self.base_time_ns))
+    }
+}
+
+/// A quality lane of the MOM. All the lanes share the same node registry,
+/// the lane only decides the socket priority (and so the taprio traffic
+/// class) used to forward messages.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LaneConfig {
+    pub name: String,
+    /// `ip:port` or `unix:/path`.
+    pub addr: String,
+    pub priority: i32,
+    /// Messages received with one `recvmmsg` and forwarded with one
+    /// `sendmmsg`. Only UDP lanes batch.
+    #[serde(default = "default_lane_batch")]
+    pub batch: usize,
+    /// Forward with `SO_TXTIME`, UDP lanes only. Without a usable etf/taprio
+    /// qdisc the lane sends without launch times.
+    #[serde(default)]
+    pub txtime: Option<TxTimeConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
     pub tasks: Vec<MomTaskConfig>,
-    pub registration_ip: String,
-    pub load_ip: String,
-    pub n_azure: i32,
+    #[serde(default)]
+    pub lanes: Vec<LaneConfig>,
+    #[serde(default = "default_max_node_usage")]
     pub max_node_usage: f64,
+    #[serde(default)]
+    pub default_policy: SelectionPolicy,
+    #[serde(default)]
+    pub topics: Vec<TopicConfig>,
+    #[serde(default)]
+    pub chains: Vec<ChainConfig>,
+    /// Time without MONITORING reports after which a node is no longer selected.
+    #[serde(default = "default_suspect_timeout_ms")]
+    pub suspect_timeout_ms: u64,
+    /// Time without MONITORING reports after which a node is removed.
+    #[serde(default = "default_dead_timeout_ms")]
+    pub dead_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        // NOTE: an empty document only contains default values, so this can't fail.
+        toml::from_str("").unwrap()
+    }
 }
 
 impl Config {
     pub fn load_config_from_file(filename: &str) -> anyhow::Result<Config> {
-        let mut file = File::open(filename)?;
+        let mut file = File::open(filename)
+            .with_context(|| format!("unable to open config file {}", filename))?;
         let mut buf = String::new();
 
-        file.read_to_string(&mut buf)?;
+        file.read_to_string(&mut buf)
+            .with_context(|| format!("unable to read config file {}", filename))?;
+
+        toml::from_str(&buf).with_context(|| format!("invalid config file {}", filename))
+    }
+
+    /// Applies the `BQADDR` and `SQADDR` environment variables, adding the
+    /// corresponding lane if the configuration does not declare it.
+    pub fn apply_env_overrides(&mut self) {
+        self.apply_overrides(|var| std::env::var(var).ok());
+    }
+
+    /// [`Config::apply_env_overrides`] with the variables read by `lookup`.
+    fn apply_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) {
+        for (var, name, priority) in LANE_ENV_VARS {
+            let addr = match lookup(var) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            match self.lanes.iter_mut().find(|lane| lane.name == name) {
+                Some(lane) => lane.addr = addr,
+                None => self.lanes.push(LaneConfig {
+                    name: name.to_string(),
+                    addr,
+                    priority,
+                    batch: DEFAULT_LANE_BATCH,
+                    txtime: None,
+                }),
+            }
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.lanes.is_empty() {
+            bail!("no lanes configured, add [[lanes]] to the config file or set BQADDR/SQADDR");
+        }
+
+        let mut names = HashSet::new();
+        for lane in &self.lanes {
+            if !names.insert(&lane.name) {
+                bail!("lane '{}' is declared more than once", lane.name);
+            }
+            let endpoint = lane
+                .addr
+                .parse::<Endpoint>()
+                .with_context(|| format!("lane '{}': invalid addr", lane.name))?;
+            if endpoint.peer().is_none() {
+                bail!(
+                    "lane '{}': {} is connection oriented, lanes need a datagram endpoint",
+                    lane.name,
+                    endpoint
+                );
+            }
+            if !(1..=MAX_LANE_BATCH).contains(&lane.batch) {
+                bail!(
+                    "lane '{}': batch must be between 1 and {}, got {}",
+                    lane.name,
+                    MAX_LANE_BATCH,
+                    lane.batch
+                );
+            }
+            if let Some(txtime) = &lane.txtime {
+                if let Err(e) = txtime.scheduler() {
+                    bail!("lane '{}': txtime {}", lane.name, e);
+                }
+                if let Err(e) = tempos::txtime::clock_from_name(&txtime.clock) {
+                    bail!("lane '{}': txtime {}", lane.name, e);
+                }
+            }
+        }
 
-        let mut config: Config = toml::from_str(&buf)?;
+        let transports = Registry::default();
+        let mut ids = HashSet::new();
+        for task in &self.tasks {
+            if !ids.insert(task.id) {
+                bail!("task {} is declared more than once", task.id);
+            }
+            for endpoint in [&task.in_endpoint, &task.out_endpoint] {
+                let uri = endpoint.parse::<Uri>().with_context(|| {
+                    format!("task {}: invalid endpoint '{}'", task.id, endpoint)
+                })?;
+                if !transports.supports(&uri.scheme) {
+                    bail!(
+                        "task {}: unsupported transport '{}' in '{}'",
+                        task.id,
+                        uri.scheme,
+                        endpoint
+                    );
+                }
+            }
+        }
+
+        let mut topics = HashSet::new();
+        for topic in &self.topics {
+            if !topics.insert(&topic.name) {
+                bail!("topic '{}' is declared more than once", topic.name);
+            }
+        }
 
-        for node in &config.nodes {
-            if node.node_type == 1 {
-                config.n_azure += 1;
+        let mut ids = HashSet::new();
+        let mut entries = HashSet::new();
+        for chain in &self.chains {
+            if chain.id == 0 {
+                bail!("chain '{}': id 0 is reserved", chain.name);
+            }
+            if !ids.insert(chain.id) {
+                bail!("chain {} is declared more than once", chain.id);
+            }
+            if chain.topics.is_empty() || chain.topics.len() > MAX_CHAIN_LEN {
+                bail!(
+                    "chain '{}': must have between 1 and {} topics, got {}",
+                    chain.name,
+                    MAX_CHAIN_LEN,
+                    chain.topics.len()
+                );
+            }
+            if !entries.insert(&chain.topics[0]) {
+                bail!(
+                    "chain '{}': topic '{}' already starts another chain",
+                    chain.name,
+                    chain.topics[0]
+                );
             }
         }
 
-        Ok(config)
+        if !(self.max_node_usage > 0.0 && self.max_node_usage <= 1.0) {
+            bail!(
+                "max_node_usage must be in (0, 1], got {}",
+                self.max_node_usage
+            );
+        }
+
+        if self.suspect_timeout_ms >= self.dead_timeout_ms {
+            bail!(
+                "suspect_timeout_ms ({}) must be lower than dead_timeout_ms ({})",
+                self.suspect_timeout_ms,
+                self.dead_timeout_ms
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LANE: &str = r#"
+        [[lanes]]
+        name = "strict"
+        addr = "127.0.0.1:3333"
+        priority = 3
+    "#;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// Asserts that `toml` is rejected by `validate` with an error containing
+    /// `message`.
+    fn rejected(toml: &str, message: &str) {
+        let err = format!("{:#}", parse(toml).validate().unwrap_err());
+        assert!(
+            err.contains(message),
+            "'{}' does not contain '{}'",
+            err,
+            message
+        );
+    }
+
+    #[test]
+    fn shipped_config_is_valid() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../config/config.toml");
+        let config = Config::load_config_from_file(path).unwrap();
+        config.validate().unwrap();
+        assert!(!config.lanes.is_empty());
+    }
+
+    #[test]
+    fn minimal_config_is_valid() {
+        let config = parse(LANE);
+        config.validate().unwrap();
+        assert_eq!(config.lanes[0].batch, DEFAULT_LANE_BATCH);
+        assert_eq!(config.max_node_usage, 1.0);
+        assert_eq!(config.suspect_timeout_ms, DEFAULT_SUSPECT_TIMEOUT_MS);
+        assert_eq!(config.dead_timeout_ms, DEFAULT_DEAD_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert!(toml::from_str::<Config>("nodes = []").is_err());
+        assert!(toml::from_str::<Config>(&format!("{}registration_ip = \"x\"", LANE)).is_err());
+    }
+
+    #[test]
+    fn no_lanes() {
+        rejected("", "no lanes configured");
+    }
+
+    #[test]
+    fn duplicate_lanes() {
+        rejected(
+            &format!("{}{}", LANE, LANE),
+            "lane 'strict' is declared more than once",
+        );
+    }
+
+    #[test]
+    fn invalid_lane_addr() {
+        rejected(
+            &LANE.replace("127.0.0.1:3333", "127.0.0.1"),
+            "lane 'strict': invalid addr",
+        );
+    }
+
+    #[test]
+    fn connection_oriented_lane() {
+        rejected(
+            &LANE.replace("127.0.0.1:3333", "unix+seqpacket:/run/tempos/mom.sock"),
+            "lanes need a datagram endpoint",
+        );
+    }
+
+    #[test]
+    fn batch_range() {
+        for batch in [0, MAX_LANE_BATCH + 1] {
+            rejected(
+                &format!("{}batch = {}", LANE, batch),
+                &format!(
+                    "batch must be between 1 and {}, got {}",
+                    MAX_LANE_BATCH, batch
+                ),
+            );
+        }
+        parse(&format!("{}batch = {}", LANE, MAX_LANE_BATCH))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn invalid_txtime() {
+        let txtime = "[lanes.txtime]\niface = \"eth0\"\ncycle_ns = 1000\n";
+        rejected(
+            &format!("{}{}window_ns = 2000", LANE, txtime),
+            "lane 'strict': txtime",
+        );
+        rejected(
+            &format!(
+                "{}{}window_ns = 500\nclock = \"CLOCK_BOOTTIME\"",
+                LANE, txtime
+            ),
+            "unknown clock 'CLOCK_BOOTTIME'",
+        );
+    }
+
+    const TASK: &str = r#"
+        [[tasks]]
+        id = 0
+        priority = 1
+        in_endpoint = "udp://127.0.0.1:3335"
+        out_endpoint = "udp://127.0.0.1:3336"
+    "#;
+
+    #[test]
+    fn duplicate_tasks() {
+        rejected(
+            &format!("{}{}{}", LANE, TASK, TASK),
+            "task 0 is declared more than once",
+        );
+    }
+
+    #[test]
+    fn invalid_task_endpoints() {
+        rejected(
+            &format!(
+                "{}{}",
+                LANE,
+                TASK.replace("udp://127.0.0.1:3336", "udp://3336")
+            ),
+            "task 0: invalid endpoint 'udp://3336'",
+        );
+        rejected(
+            &format!(
+                "{}{}",
+                LANE,
+                TASK.replace("udp://127.0.0.1:3335", "sctp://10.0.0.2:4000")
+            ),
+            "task 0: unsupported transport 'sctp'",
+        );
+    }
+
+    #[test]
+    fn duplicate_topics() {
+        let topic = "[[topics]]\nname = \"vpn\"\npolicy = \"round_robin\"\n";
+        rejected(
+            &format!("{}{}{}", LANE, topic, topic),
+            "topic 'vpn' is declared more than once",
+        );
+    }
+
+    fn chain(id: u16, name: &str, topics: &[&str]) -> String {
+        format!(
+            "[[chains]]\nid = {}\nname = \"{}\"\ntopics = {:?}\n",
+            id, name, topics
+        )
+    }
+
+    #[test]
+    fn chain_id_0_is_reserved() {
+        rejected(
+            &format!("{}{}", LANE, chain(0, "vpn", &["a"])),
+            "chain 'vpn': id 0 is reserved",
+        );
+    }
+
+    #[test]
+    fn duplicate_chains() {
+        rejected(
+            &format!(
+                "{}{}{}",
+                LANE,
+                chain(1, "vpn", &["a"]),
+                chain(1, "fw", &["b"])
+            ),
+            "chain 1 is declared more than once",
+        );
+    }
+
+    #[test]
+    fn chain_length() {
+        let long = vec!["t"; MAX_CHAIN_LEN + 1];
+        for topics in [&[][..], &long[..]] {
+            rejected(
+                &format!("{}{}", LANE, chain(1, "vpn", topics)),
+                &format!(
+                    "chain 'vpn': must have between 1 and {} topics, got {}",
+                    MAX_CHAIN_LEN,
+                    topics.len()
+                ),
+            );
+        }
+        parse(&format!("{}{}", LANE, chain(1, "vpn", &long[1..])))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn chains_with_the_same_entry() {
+        rejected(
+            &format!(
+                "{}{}{}",
+                LANE,
+                chain(1, "vpn", &["a", "b"]),
+                chain(2, "fw", &["a"])
+            ),
+            "chain 'fw': topic 'a' already starts another chain",
+        );
+    }
+
+    #[test]
+    fn max_node_usage() {
+        for usage in ["0.0", "-0.5", "1.5", "nan"] {
+            rejected(
+                &format!("max_node_usage = {}\n{}", usage, LANE),
+                "max_node_usage must be in (0, 1]",
+            );
+        }
+        parse(&format!("max_node_usage = 0.8\n{}", LANE))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn suspect_timeout_before_dead_timeout() {
+        for (suspect, dead) in [(100, 100), (200, 100)] {
+            rejected(
+                &format!(
+                    "suspect_timeout_ms = {}\ndead_timeout_ms = {}\n{}",
+                    suspect, dead, LANE
+                ),
+                &format!(
+                    "suspect_timeout_ms ({}) must be lower than dead_timeout_ms ({})",
+                    suspect, dead
+                ),
+            );
+        }
+    }
+
+    #[test]
+    fn env_overrides_replace_lane_addresses() {
+        let mut config = parse(LANE);
+        config.apply_overrides(|var| (var == "SQADDR").then(|| "unix:/run/mom.sock".to_string()));
+
+        assert_eq!(config.lanes.len(), 1);
+        assert_eq!(config.lanes[0].addr, "unix:/run/mom.sock");
+        assert_eq!(config.lanes[0].priority, 3);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn env_overrides_add_missing_lanes() {
+        let mut config = parse(LANE);
+        config.apply_overrides(|var| (var == "BQADDR").then(|| "127.0.0.1:3334".to_string()));
+
+        assert_eq!(config.lanes.len(), 2);
+        assert_eq!(config.lanes[0].addr, "127.0.0.1:3333");
+        let lane = &config.lanes[1];
+        assert_eq!(lane.name, "best-effort");
+        assert_eq!(lane.addr, "127.0.0.1:3334");
+        assert_eq!(lane.priority, BE_LANE_PRIORITY);
+        assert_eq!(lane.batch, DEFAULT_LANE_BATCH);
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.apply_overrides(|var| match var {
+            "BQADDR" => Some("udp://127.0.0.1:3334".to_string()),
+            _ => Some("udp://127.0.0.1:3333".to_string()),
+        });
+        let lanes: Vec<_> = config
+            .lanes
+            .iter()
+            .map(|lane| (lane.name.as_str(), lane.priority))
+            .collect();
+        assert_eq!(
+            lanes,
+            [
+                ("best-effort", BE_LANE_PRIORITY),
+                ("strict", STRICT_LANE_PRIORITY)
+            ]
+        );
+        config.validate().unwrap();
     }
 }
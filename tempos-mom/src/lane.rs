@@ -0,0 +1,614 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tempos::batch::{RecvBatch, SendBatch};
+use tempos::buffer::{Buffer, BufferPool};
+use tempos::endpoint::{Endpoint, PeerAddr, TemposSocket};
+use tempos::failure;
+use tempos::message::{decode, TemposMessage, MAX_MESSAGE_LEN};
+use tempos::shm::ShmRing;
+use tempos::txtime::{TxTimeErrors, TxTimeSocket};
+#[cfg(feature = "uring")]
+use tempos::uring::UringSocket;
+
+use crate::chains::{Chains, Route};
+use crate::config::LaneConfig;
+use crate::origins::Origins;
+use crate::registry::{CoreReader, SharedCore};
+
+/// How often the launch-time reports of the qdisc are collected.
+const TXTIME_ERRORS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A quality lane: receives the control messages and the INVOKs of its
+/// priority class and forwards the latter to the selected nodes.
+pub struct Lane {
+    name: String,
+    sock: TemposSocket,
+    core: CoreReader,
+    chains: Arc<Chains>,
+    origins: Arc<Origins>,
+    /// Chained INVOKs are stamped here before replacing the received one.
+    scratch: Buffer,
+    batch: usize,
+    txtime: Option<TxTimeSocket>,
+    txtime_errors: TxTimeErrors,
+    txtime_checked: Instant,
+    dropped: u64,
+    expired: u64,
+    failed: u64,
+}
+
+impl Lane {
+    pub fn new(
+        config: &LaneConfig,
+        core: Arc<SharedCore>,
+        chains: Arc<Chains>,
+        origins: Arc<Origins>,
+    ) -> anyhow::Result<Self> {
+        let endpoint: Endpoint = config.addr.parse()?;
+        let sock = TemposSocket::bind(&endpoint)?;
+        // NOTE: the priority only matters for traffic leaving the host.
+        if sock.is_inet() {
+            if let Err(e) = tempos::set_priority(&sock, config.priority) {
+                log::warn!(
+                    "unable to set priority {} on the {} lane: {}",
+                    config.priority,
+                    config.name,
+                    e
+                );
+            }
+        } else if config.batch > 1 {
+            log::warn!(
+                "{} lane: batching is only available on UDP, {} is served one message at a time",
+                config.name,
+                endpoint
+            );
+        }
+        sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        let txtime = match &config.txtime {
+            Some(_) if !sock.is_inet() => {
+                log::warn!(
+                    "{} lane: txtime only applies to UDP, {} ignores it",
+                    config.name,
+                    endpoint
+                );
+                None
+            }
+            Some(txtime) => match TxTimeSocket::open(
+                config.priority,
+                &txtime.iface,
+                tempos::txtime::clock_from_name(&txtime.clock)?,
+                txtime.scheduler()?,
+            ) {
+                Ok(sock) => {
+                    log::info!(
+                        "{} lane: forwarding with SO_TXTIME through {}",
+                        config.name,
+                        txtime.iface
+                    );
+                    Some(sock)
+                }
+                Err(e) => {
+                    log::warn!(
+                        "{} lane: SO_TXTIME unavailable on {}, sending without launch times: {}",
+                        config.name,
+                        txtime.iface,
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            name: config.name.clone(),
+            batch: if sock.is_inet() { config.batch } else { 1 },
+            sock,
+            txtime,
+            txtime_errors: TxTimeErrors::default(),
+            txtime_checked: Instant::now(),
+            core: CoreReader::new(core),
+            chains,
+            origins,
+            scratch: Buffer::with_capacity(MAX_MESSAGE_LEN, 0),
+            dropped: 0,
+            expired: 0,
+            failed: 0,
+        })
+    }
+
+    pub fn run(&mut self, pool: &BufferPool, r: Arc<AtomicBool>) -> anyhow::Result<()> {
+        #[cfg(feature = "uring")]
+        match UringSocket::new(
+            &self.sock,
+            tempos::uring::DEFAULT_RECV_BUFFERS,
+            self.batch.max(tempos::uring::DEFAULT_SEND_SLOTS),
+        ) {
+            Ok(uring) => {
+                log::info!("{} lane: using io_uring", self.name);
+                return self.run_uring(pool, uring, r);
+            }
+            Err(e) => log::warn!(
+                "{} lane: io_uring unavailable, falling back to the socket loop: {}",
+                self.name,
+                e
+            ),
+        }
+
+        if self.batch > 1 {
+            self.run_batched(pool, r)
+        } else {
+            self.run_single(pool, r)
+        }
+    }
+
+    /// One `recv_from` and one `send_to` per message.
+    fn run_single(&mut self, pool: &BufferPool, r: Arc<AtomicBool>) -> anyhow::Result<()> {
+        let mut buf = pool
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("no receive buffer left for the {} lane", self.name))?;
+
+        while r.load(Ordering::Relaxed) {
+            self.check_txtime_errors();
+            buf.clear();
+            let (bytes_read, addr) = match self.sock.recv_from(buf.spare_mut()) {
+                Ok(received) => received,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            buf.commit(bytes_read);
+
+            if let Some(peer) = self.handle(&mut buf, addr) {
+                if let Err(e) = self.sock.send_to(buf.as_slice(), &peer) {
+                    log::error!("Error sending INVOK message: {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Up to `batch` messages per `recvmmsg`, the INVOKs of a batch are
+    /// forwarded with a single `sendmmsg`.
+    fn run_batched(&mut self, pool: &BufferPool, r: Arc<AtomicBool>) -> anyhow::Result<()> {
+        let mut bufs = Vec::with_capacity(self.batch);
+        for _ in 0..self.batch {
+            bufs.push(pool.get().ok_or_else(|| {
+                anyhow::anyhow!("no receive buffer left for the {} lane", self.name)
+            })?);
+        }
+        let mut targets: Vec<Option<SocketAddr>> = vec![None; self.batch];
+        let mut recv_batch = RecvBatch::new(self.batch);
+        let mut send_batch = SendBatch::new(self.batch);
+
+        while r.load(Ordering::Relaxed) {
+            self.check_txtime_errors();
+            bufs.iter_mut().for_each(|buf| buf.clear());
+            let received = match recv_batch.recv(&self.sock, &mut bufs) {
+                Ok(received) => received,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            for i in 0..received {
+                targets[i] = None;
+                let addr = recv_batch.addr(i).map(PeerAddr::Udp);
+                match self.handle(&mut bufs[i], addr) {
+                    Some(PeerAddr::Udp(addr)) => targets[i] = Some(addr),
+                    Some(peer) => {
+                        if let Err(e) = self.sock.send_to(bufs[i].as_slice(), &peer) {
+                            log::error!("Error sending INVOK message: {}", e);
+                        }
+                    }
+                    None => {}
+                }
+            }
+
+            let forwards =
+                (0..received).filter_map(|i| targets[i].map(|addr| (bufs[i].as_slice(), addr)));
+            let pending = targets[..received].iter().flatten().count();
+            let report = send_batch.send(&self.sock, forwards);
+            if let Some(e) = report.error {
+                log::error!(
+                    "Error sending INVOK messages: {} of {} not sent: {}",
+                    report.dropped,
+                    pending,
+                    e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receives and forwards through an io_uring: the lane sleeps in the
+    /// kernel while idle and the forwards are submitted with the next wait.
+    #[cfg(feature = "uring")]
+    fn run_uring(
+        &mut self,
+        pool: &BufferPool,
+        mut uring: UringSocket,
+        r: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        let mut buf = pool
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("no receive buffer left for the {} lane", self.name))?;
+        let mut send_errors = 0;
+
+        while r.load(Ordering::Relaxed) {
+            self.check_txtime_errors();
+            let addr = match uring.recv(&mut buf, Duration::from_millis(100)) {
+                Ok(addr) => addr,
+                Err(e) if is_transient(&e) => continue,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.dropped += 1;
+                    log::warn!("dropping message: {} [{} dropped]", e, self.dropped);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(peer) = self.handle(&mut buf, addr) {
+                if let Err(e) = uring.send_to(buf.as_slice(), &peer) {
+                    log::error!("Error sending INVOK message: {}", e);
+                }
+            }
+
+            if uring.send_errors() > send_errors {
+                log::error!(
+                    "Error sending INVOK messages: {} failed",
+                    uring.send_errors() - send_errors
+                );
+                send_errors = uring.send_errors();
+            }
+        }
+
+        uring.flush()?;
+        Ok(())
+    }
+
+    /// Logs the launch-time errors reported by the qdisc since the last
+    /// check, at most once per `TXTIME_ERRORS_INTERVAL`.
+    fn check_txtime_errors(&mut self) {
+        let txtime = match &self.txtime {
+            Some(txtime) if self.txtime_checked.elapsed() >= TXTIME_ERRORS_INTERVAL => txtime,
+            _ => return,
+        };
+        self.txtime_checked = Instant::now();
+
+        match txtime.errors() {
+            Ok(errors) if errors.is_empty() => {}
+            Ok(errors) => {
+                self.txtime_errors.missed += errors.missed;
+                self.txtime_errors.invalid += errors.invalid;
+                log::warn!(
+                    "{} lane: {} INVOKs dropped by the qdisc past their launch time, {} rejected [{} missed, {} invalid]",
+                    self.name,
+                    errors.missed,
+                    errors.invalid,
+                    self.txtime_errors.missed,
+                    self.txtime_errors.invalid
+                );
+            }
+            Err(e) => log::error!(
+                "{} lane: unable to read the txtime errors: {}",
+                self.name,
+                e
+            ),
+        }
+    }
+
+    /// Handles one message. Returns the channel of the node selected for an
+    /// INVOK, to which `buf` must be forwarded, unless it was handed over
+    /// through its ring or sent with a launch time. Chained INVOKs are
+    /// restamped in `buf` first. FAILUREs are returned to the sender of
+    /// their INVOK.
+    fn handle(&mut self, buf: &mut Buffer, addr: Option<PeerAddr>) -> Option<PeerAddr> {
+        let (header, msg) = match decode(buf.as_slice()) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.dropped += 1;
+                log::warn!(
+                    "dropping malformed message from {} ({} bytes): {} [{} dropped]",
+                    addr.as_ref()
+                        .map_or_else(|| "an unbound socket".to_string(), |a| a.to_string()),
+                    buf.len(),
+                    e,
+                    self.dropped
+                );
+                return None;
+            }
+        };
+
+        match msg {
+            TemposMessage::Registration { node_id, topic } => {
+                let channel = match addr {
+                    Some(addr) => addr,
+                    None => {
+                        log::warn!(
+                            "REGISTRATION from node {} on an unbound socket, ignoring it",
+                            node_id
+                        );
+                        return None;
+                    }
+                };
+
+                // NOTE: the ring is opened before taking the write lock. It only
+                //       exists if the node runs on this host, remote nodes
+                //       are reached through the socket.
+                let ring = if header.flags & tempos::flags::SHM_RING != 0 {
+                    let path = tempos::shm::ring_path(node_id);
+                    match ShmRing::open(&path) {
+                        Ok(ring) => {
+                            log::info!("node {} reachable through {}", node_id, path.display());
+                            Some(ring)
+                        }
+                        Err(e) => {
+                            log::warn!(
+                                "unable to open the ring of node {}, using {}: {}",
+                                node_id,
+                                channel,
+                                e
+                            );
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                self.core.update(|core| {
+                    if let Some(event) = core.add_node(node_id, channel, ring) {
+                        event.log();
+                    }
+                    if !core.subscribe(topic, node_id) {
+                        log::debug!("node {} already subscribed to topic {}", node_id, topic);
+                    }
+                });
+                log::debug!("REGISTRATION message from {} for topic {}", node_id, topic);
+            }
+            TemposMessage::Monitoring {
+                node_id,
+                load,
+                memory,
+                in_flight,
+                warm,
+            } => {
+                // normalize the load from 0 to 100
+                let load = (load * 100.0) as u32;
+                let memory = (memory * 100.0) as u32;
+                log::trace!(
+                    "MONITORING from {}: load={} memory={} in_flight={} warm={}",
+                    node_id,
+                    load,
+                    memory,
+                    in_flight,
+                    warm
+                );
+                let core = self.core.get();
+                core.update_node_load(node_id, load);
+                core.update_node_status(node_id, memory, in_flight, warm);
+                if let Some(event) = core.touch_node(node_id, Instant::now()) {
+                    event.log();
+                }
+            }
+            TemposMessage::Unregistration { node_id } => {
+                log::debug!("UNREGISTRATION message from {}", node_id);
+                if let Some(event) = self.core.update(|core| core.remove_node(node_id)) {
+                    event.log();
+                }
+            }
+            TemposMessage::Unsubscribe { node_id, topic } => {
+                log::debug!("UNSUBSCRIBE message from {} for topic {}", node_id, topic);
+                if !self.core.update(|core| core.unsubscribe(topic, node_id)) {
+                    log::debug!("node {} was not subscribed to topic {}", node_id, topic);
+                }
+            }
+            TemposMessage::Failure {
+                seq,
+                chain,
+                hop,
+                kind,
+                status,
+                topic,
+                reason,
+            } => {
+                self.failed += 1;
+                log::warn!(
+                    "INVOK {} failed on topic '{}' (chain {}, hop {}, status {}): {}: {} [{} failed]",
+                    seq,
+                    topic,
+                    chain,
+                    hop,
+                    status,
+                    failure::name(kind),
+                    reason,
+                    self.failed
+                );
+                match self.origins.get(seq) {
+                    Some(origin) => return Some(origin),
+                    None => log::debug!("sender of INVOK {} unknown, failure not reported", seq),
+                }
+            }
+            TemposMessage::Invok {
+                seq,
+                chain,
+                hop,
+                topic,
+                data,
+            } => {
+                let now = tempos::now_ns();
+                if header.is_expired(now) {
+                    self.expired += 1;
+                    log::warn!(
+                        "dropping INVOK {} for topic '{}', deadline missed by {} ns [{} expired]",
+                        seq,
+                        topic,
+                        now - header.deadline,
+                        self.expired
+                    );
+                    return None;
+                }
+                log::trace!(
+                    "INVOK {} reached the MOM after {} ns",
+                    seq,
+                    header.latency_ns(now)
+                );
+
+                // NOTE: INVOKs of a chain are stamped with the chain id, hop
+                //       and topic resolved here, the others are forwarded as
+                //       received.
+                let mut restamped = false;
+                let topic = match self.chains.route(chain, hop, topic) {
+                    Route::Topic => topic,
+                    Route::Hop {
+                        chain: id,
+                        hop: index,
+                        topic: next,
+                    } => {
+                        if (id, index, next) != (chain, hop, topic) {
+                            self.scratch.clear();
+                            let stamped = TemposMessage::Invok {
+                                seq,
+                                chain: id,
+                                hop: index,
+                                topic: next,
+                                data,
+                            }
+                            .encode_into(&header, &mut self.scratch);
+                            if let Err(e) = stamped {
+                                self.dropped += 1;
+                                log::warn!(
+                                    "dropping INVOK {} for hop {} of chain {}: {} [{} dropped]",
+                                    seq,
+                                    index,
+                                    id,
+                                    e,
+                                    self.dropped
+                                );
+                                return None;
+                            }
+                            restamped = true;
+                        }
+                        log::trace!("INVOK {} is hop {} of chain {}", seq, index, id);
+                        next
+                    }
+                    Route::Completed { chain, name } => {
+                        log::debug!("INVOK {} completed chain {} ({})", seq, chain, name);
+                        return None;
+                    }
+                    Route::Unknown { chain } => {
+                        self.dropped += 1;
+                        log::warn!(
+                            "dropping INVOK {} for unknown chain {} [{} dropped]",
+                            seq,
+                            chain,
+                            self.dropped
+                        );
+                        return None;
+                    }
+                };
+
+                let core = self.core.get();
+                // NOTE: the outputs forwarded by the invokers keep the sequence
+                //       number of the INVOK they answer, only the first one is
+                //       sent by the trigger.
+                if let (0, 0, Some(addr)) = (chain, hop, &addr) {
+                    if !core.has_channel(addr) {
+                        self.origins.record(seq, addr);
+                    }
+                }
+                if core.get_topic(topic).is_none() {
+                    log::warn!("No node registered for topic '{}'", topic);
+                    return None;
+                }
+
+                let node = match core.select_node(topic) {
+                    Some(node) => node,
+                    None => {
+                        log::warn!("No available node for topic '{}'", topic);
+                        return None;
+                    }
+                };
+
+                log::debug!(
+                    "Sending INVOK {} for topic '{}' ({} bytes) to {}",
+                    seq,
+                    topic,
+                    if restamped {
+                        self.scratch.len()
+                    } else {
+                        buf.len()
+                    },
+                    node.channel
+                );
+                if restamped && buf.set_data(self.scratch.as_slice()).is_err() {
+                    self.dropped += 1;
+                    log::warn!(
+                        "dropping INVOK {}, stamped message larger than the receive buffer [{} dropped]",
+                        seq,
+                        self.dropped
+                    );
+                    return None;
+                }
+
+                if let Some(ring) = &node.ring {
+                    match ring.push(buf.as_slice()) {
+                        Ok(()) => return None,
+                        Err(e) => log::debug!(
+                            "ring of node {}: {}, falling back to {}",
+                            node.id,
+                            e,
+                            node.channel
+                        ),
+                    }
+                }
+
+                if let (Some(txtime), PeerAddr::Udp(addr)) = (&self.txtime, &node.channel) {
+                    match txtime.send_to(buf.as_slice(), *addr, header.deadline) {
+                        Ok(_) => return None,
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                            self.expired += 1;
+                            log::warn!(
+                                "dropping INVOK {} for {}: {} [{} expired]",
+                                seq,
+                                addr,
+                                e,
+                                self.expired
+                            );
+                            return None;
+                        }
+                        Err(e) => log::warn!(
+                            "SO_TXTIME send to {} failed, sending without launch time: {}",
+                            addr,
+                            e
+                        ),
+                    }
+                }
+
+                return Some(node.channel.clone());
+            }
+        }
+
+        None
+    }
+}
+
+/// Receive errors after which the lane keeps going.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        io::ErrorKind::ConnectionRefused => {
+            // NOTE: an ICMP port unreachable from a previous send_to is
+            //       reported on the next receive, the lane must survive it.
+            log::warn!("previous send was refused by the peer");
+            true
+        }
+        _ => false,
+    }
+}
@@ -1,249 +1,175 @@
+mod chains;
 mod config;
+mod lane;
+mod origins;
+mod policy;
+mod registry;
 mod task;
+mod transport;
 
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-
-struct UdpAdapter {
-    // source_sock: UdpSocket,
-    // source_addr: SocketAddr,
-    sink_sock: UdpSocket,
-    sink_addr: SocketAddr,
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tempos::buffer::BufferPool;
+use tempos::message::MAX_MESSAGE_LEN;
+
+use chains::Chains;
+use clap::Parser;
+use config::Config;
+use lane::Lane;
+use origins::{Origins, ORIGIN_SLOTS};
+use registry::{Core, MembershipEvent, SharedCore};
+use task::MomTask;
+use transport::Registry;
+
+const NODE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// TEMPOS Message Oriented Middleware
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// TOML configuration file, BQADDR and SQADDR override its lane addresses
+    #[clap(short, long)]
+    config: Option<String>,
 }
 
-impl UdpAdapter {
-    pub fn new(config: &str) -> anyhow::Result<Self> {
-        let cfg: Vec<&str> = config.split(&"?").collect();
-        let addr = cfg.get(0).unwrap_or(&"");
-
-        let props_str: Vec<&str> = cfg.get(1).unwrap_or(&"").split(&['&', '=']).collect();
-        let mut props = HashMap::new();
-        for i in (0..props_str.len()).step_by(2) {
-            props.insert(props_str[i], props_str[i + 1]);
-        }
-
-        let sink_sock = UdpSocket::bind(addr)?;
-        sink_sock.set_read_timeout(Some(Duration::from_millis(100)))?;
-
-        let sink_addr: SocketAddr = props.get("sink").unwrap().parse()?;
-
-        Ok(Self {
-            sink_sock,
-            sink_addr,
-        })
-    }
-
-    pub fn read(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
-        let (bytes_read, _) = self.sink_sock.recv_from(buf)?;
-
-        Ok(bytes_read)
-    }
-
-    pub fn write(&self, buf: &[u8]) -> anyhow::Result<()> {
-        self.sink_sock.send_to(&buf, &self.sink_addr)?;
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
 
-        Ok(())
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => Config::load_config_from_file(path)?,
+        None => Config::default(),
+    };
+    config.apply_env_overrides();
+    config.validate()?;
+
+    for lane in &config.lanes {
+        log::info!(
+            "Starting TEMPOS MOM {} lane on {} (priority {}, batch {})",
+            lane.name,
+            lane.addr,
+            lane.priority,
+            lane.batch
+        );
     }
-}
-
-const UDP_ADAPTER_CONFIG: &str = "udp/127.0.0.1:8001?sink=127.0.0.1:8002&prova=hello";
-
-#[derive(Debug, PartialEq, Eq, Hash)]
-struct Node {
-    id: u32,
-    load: u32,
-    channel: SocketAddr,
-}
 
-struct Core {
-    nodes: HashMap<u32, Node>,
-    topics: HashMap<String, Vec<u32>>,
-    // topics: HashMap<String, (Vec<u32>, Vec<u32>)>,
-}
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, std::sync::atomic::Ordering::Relaxed);
+    })?;
 
-impl Core {
-    pub fn new() -> Self {
-        Self {
-            nodes: HashMap::new(),
-            topics: HashMap::new(),
-        }
+    let core = Arc::new(SharedCore::new(Core::from_config(&config)));
+    let chains = Arc::new(Chains::from_config(&config.chains));
+    log::info!("{} function chains configured", chains.len());
+    let origins = Arc::new(Origins::new(ORIGIN_SLOTS));
+
+    let transports = Registry::default();
+
+    // NOTE: one receive buffer per batch slot of each lane and one per task,
+    //       allocated up front.
+    let pool = Arc::new(BufferPool::new(
+        config.lanes.iter().map(|lane| lane.batch).sum::<usize>() + config.tasks.len(),
+        MAX_MESSAGE_LEN,
+        0,
+    ));
+
+    let mut handles = vec![];
+    for task_config in &config.tasks {
+        let mut task = MomTask::new(task_config, &transports, core.clone(), chains.clone())?;
+        log::info!(
+            "Starting MOM task {} {} -> {} (priority {})",
+            task_config.id,
+            task_config.in_endpoint,
+            task_config.out_endpoint,
+            task_config.priority
+        );
+
+        let pool = pool.clone();
+        handles.push(spawn(
+            format!("task {}", task_config.id),
+            running.clone(),
+            move |r| task.run(&pool, r),
+        ));
     }
 
-    pub fn add_node(&mut self, id: u32, channel: SocketAddr) {
-        log::debug!("adding node {} at {}", id, channel);
-        let node = Node {
-            id: id,
-            load: 0,
-            channel: channel,
-        };
-        self.nodes.insert(id, node);
+    for lane_config in &config.lanes {
+        let mut lane = Lane::new(lane_config, core.clone(), chains.clone(), origins.clone())?;
+        let pool = pool.clone();
+        handles.push(spawn(
+            format!("{} lane", lane_config.name),
+            running.clone(),
+            move |r| lane.run(&pool, r),
+        ));
     }
 
-    pub fn remove_node(&mut self, id: u32) {
-        self.nodes.remove(&id);
-    }
+    membership_loop(core, running);
 
-    pub fn update_node_load(&mut self, id: u32, load: u32) {
-        if let Some(mut node) = self.nodes.get_mut(&id) {
-            node.load = load;
+    // NOTE: the first failure stopped the MOM, it is the one reported.
+    let mut res = Ok(());
+    for (name, handle) in handles {
+        let joined = handle
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("{} panicked", name)));
+        if let (Ok(()), Err(e)) = (&res, joined) {
+            res = Err(e.context(name));
         }
     }
 
-    pub fn get_topic(&self, topic: &str) -> Option<&Vec<u32>> {
-        self.topics.get(topic)
-    }
-
-    pub fn get_topic_mut(&mut self, topic: &str) -> Option<&mut Vec<u32>> {
-        self.topics.get_mut(topic)
-    }
+    res
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
-    let be_quality_addr = std::env::var("BQADDR")?;
-    let strict_quality_addr = std::env::var("SQADDR")?;
-
-    log::info!(
-        "Starting TEMPOS MOM with BQADDR={} and SQADDR={}",
-        be_quality_addr,
-        strict_quality_addr
-    );
-
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, std::sync::atomic::Ordering::Relaxed);
-    })?;
-
-    let r = running.clone();
-    let t1 = std::thread::spawn(move || {
-        main_func(&be_quality_addr, r).unwrap();
-    });
-
-    let t2 = std::thread::spawn(move || {
-        main_func(&strict_quality_addr, running).unwrap();
+/// Runs `f` on its own thread. If it fails the error is logged and
+/// `running` cleared, so that the whole MOM stops instead of going on
+/// without `name`.
+fn spawn<F>(
+    name: String,
+    running: Arc<AtomicBool>,
+    f: F,
+) -> (String, JoinHandle<anyhow::Result<()>>)
+where
+    F: FnOnce(Arc<AtomicBool>) -> anyhow::Result<()> + Send + 'static,
+{
+    let thread_name = name.clone();
+    let handle = std::thread::spawn(move || {
+        let res = f(running.clone());
+        if let Err(e) = &res {
+            log::error!("{} failed, stopping the MOM: {:#}", thread_name, e);
+            running.store(false, Ordering::Relaxed);
+        }
+        res
     });
 
-    t1.join().unwrap();
-    t2.join().unwrap();
-
-    Ok(())
+    (name, handle)
 }
 
-fn main_func(addr: &str, r: Arc<AtomicBool>) -> anyhow::Result<()> {
-    let sock = UdpSocket::bind(addr)?;
+/// Periodically checks the liveness of the registered nodes and removes the
+/// expired ones.
+fn membership_loop(core: Arc<SharedCore>, r: Arc<AtomicBool>) {
+    while r.load(Ordering::Relaxed) {
+        std::thread::sleep(NODE_CHECK_INTERVAL);
 
-    let mut buf = [0; 2048];
-    let mut msg_type;
-    let mut core = Core::new();
-    sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let now = Instant::now();
+        let events = core.load().check_nodes(now);
+        if events.is_empty() {
+            continue;
+        }
 
-    while r.load(Ordering::Relaxed) {
-        // receive a message handling the timeout
-        let (bytes_read, addr) = match sock.recv_from(&mut buf) {
-            Ok((bytes_read, addr)) => (bytes_read, addr),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    // println!("Nodes: {:?}", core.nodes);
-                    continue;
-                } else {
-                    return Err(e.into());
-                }
-            }
-        };
-
-        msg_type = u8::from_be_bytes([buf[0]]);
-
-        match msg_type {
-            tempos::msg_type::REGISTRATION => {
-                let node_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
-                let topic_len = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
-                let topic = std::str::from_utf8(&buf[9..9 + topic_len as usize])?;
-                core.add_node(node_id, addr);
-                if let Some(topic) = core.get_topic_mut(topic) {
-                    topic.push(node_id);
-                } else {
-                    core.topics.insert(topic.to_string(), vec![node_id]);
-                }
-                log::debug!("REGISTRATION message from {} for topic {}", node_id, topic);
-            }
-            tempos::msg_type::MONITORING => {
-                let node_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
-                let load = f32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
-                // normalize the load from 0 to 100
-                let load = (load * 100.0) as u32;
-                core.update_node_load(node_id, load);
-            }
-            tempos::msg_type::UNREGISTRATION => {
-                let node_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
-                log::debug!("UNREGISTRATION message from {}", node_id);
-                core.remove_node(node_id);
-            }
-            tempos::msg_type::INVOK => {
-                // let msg_seq = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
-                let topic_len = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
-                let topic = std::str::from_utf8(&buf[9..9 + topic_len as usize])?;
-
-                if let Some(topic) = core.get_topic(topic) {
-                    if !topic.is_empty() {
-                        let node_id = topic[0];
-                        let node = core.nodes.get(&node_id).unwrap();
-                        let addr = node.channel;
-                        log::debug!(
-                            "Sending INVOK message {:?} ({} bytes) to {}",
-                            &buf[0..bytes_read],
-                            bytes_read,
-                            addr
-                        );
-                        match sock.send_to(&buf[0..bytes_read], addr) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("Error sending INVOK message: {}", e);
-                            }
-                        }
+        // NOTE: a node may have registered or reported again since the
+        //       check, its expiry is confirmed on the latest snapshot.
+        core.update(|core| {
+            for event in events {
+                event.log();
+                if let MembershipEvent::Expired(id) = event {
+                    if let Some(event) = core.remove_expired(id, now) {
+                        event.log();
                     }
-                } else {
-                    log::warn!("No node registered for topic '{}'", topic);
                 }
             }
-            _ => println!("Unhandled message type"),
-        }
+        });
     }
-
-    Ok(())
-}
-
-fn one_thread() {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = Arc::clone(&running);
-    ctrlc::set_handler(move || {
-        println!("Received Ctrl+C, exiting...");
-        r.store(false, Ordering::Relaxed);
-    })
-    .unwrap();
-
-    let mut buf = [0u8; 4096];
-    let t_handle = std::thread::spawn(move || {
-        let strs: Vec<&str> = UDP_ADAPTER_CONFIG.split('/').collect();
-
-        let proto = strs.get(0).unwrap_or(&"");
-        let config = strs.get(1).unwrap_or(&"");
-
-        let adapter = match proto {
-            &"udp" => UdpAdapter::new(config).unwrap(),
-            _ => UdpAdapter::new(config).unwrap(),
-        };
-
-        while running.load(Ordering::Relaxed) {
-            if let Ok(_) = adapter.read(&mut buf) {
-                adapter.write(&buf).unwrap();
-            }
-        }
-    });
-
-    t_handle.join().unwrap();
 }
@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+
+use tempos::endpoint::PeerAddr;
+
+/// Number of INVOKs whose sender is remembered.
+pub const ORIGIN_SLOTS: usize = 4096;
+
+/// Senders of the INVOKs entering the MOM, to which the failures of their
+/// invocations are reported.
+///
+/// Shared by the lanes, as a failure may come back through another lane
+/// than its INVOK. Slots are indexed by sequence number: the sender of an
+/// INVOK is forgotten once `ORIGIN_SLOTS` later ones were received, or when
+/// another trigger sends one with the same sequence number.
+pub struct Origins {
+    slots: Vec<Mutex<Option<(u32, PeerAddr)>>>,
+}
+
+impl Origins {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: (0..slots.max(1)).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    fn slot(&self, seq: u32) -> &Mutex<Option<(u32, PeerAddr)>> {
+        &self.slots[seq as usize % self.slots.len()]
+    }
+
+    /// Remembers `addr` as the sender of `seq`, in place of any previous one.
+    pub fn record(&self, seq: u32, addr: &PeerAddr) {
+        *self.slot(seq).lock().unwrap() = Some((seq, addr.clone()));
+    }
+
+    pub fn get(&self, seq: u32) -> Option<PeerAddr> {
+        match &*self.slot(seq).lock().unwrap() {
+            Some((known, addr)) if *known == seq => Some(addr.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn addr(port: u16) -> PeerAddr {
+        PeerAddr::Udp(([127, 0, 0, 1], port).into())
+    }
+
+    #[test]
+    fn record_and_get() {
+        let origins = Origins::new(8);
+        origins.record(1, &addr(1));
+        origins.record(2, &addr(2));
+        let unix = PeerAddr::Unix(PathBuf::from("/run/tempos/trigger.sock"));
+        origins.record(3, &unix);
+
+        assert_eq!(origins.get(1), Some(addr(1)));
+        assert_eq!(origins.get(2), Some(addr(2)));
+        assert_eq!(origins.get(3), Some(unix));
+        // NOTE: a failure may be reported more than once per INVOK.
+        assert_eq!(origins.get(1), Some(addr(1)));
+
+        origins.record(1, &addr(10));
+        assert_eq!(origins.get(1), Some(addr(10)));
+    }
+
+    #[test]
+    fn missing_seq() {
+        let origins = Origins::new(8);
+        assert_eq!(origins.get(0), None);
+        assert_eq!(origins.get(5), None);
+
+        // NOTE: seq 12 shares the slot of seq 4.
+        origins.record(4, &addr(4));
+        assert_eq!(origins.get(12), None);
+        assert_eq!(origins.get(4), Some(addr(4)));
+    }
+
+    #[test]
+    fn wrapped_seq_overwrites_the_stale_slot() {
+        let origins = Origins::new(8);
+        origins.record(3, &addr(1));
+        origins.record(11, &addr(2));
+
+        // NOTE: the failure of the old INVOK must not reach the new sender.
+        assert_eq!(origins.get(3), None);
+        assert_eq!(origins.get(11), Some(addr(2)));
+
+        // NOTE: the sequence numbers wrap around u32 onto the same slot.
+        origins.record(u32::MAX, &addr(3));
+        assert_eq!(origins.get(u32::MAX), Some(addr(3)));
+        origins.record(u32::MAX.wrapping_add(8), &addr(4));
+        assert_eq!(origins.get(u32::MAX), None);
+        assert_eq!(origins.get(7), Some(addr(4)));
+    }
+
+    #[test]
+    fn at_least_one_slot() {
+        let origins = Origins::new(0);
+        origins.record(1, &addr(1));
+        assert_eq!(origins.get(1), Some(addr(1)));
+        origins.record(2, &addr(2));
+        assert_eq!(origins.get(1), None);
+        assert_eq!(origins.get(2), Some(addr(2)));
+    }
+}
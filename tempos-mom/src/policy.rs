@@ -0,0 +1,236 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::registry::Node;
+
+/// Strategy used to pick the node that receives an INVOK for a topic.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionPolicy {
+    #[default]
+    LeastLoaded,
+    RoundRobin,
+    /// Random choice weighted by the spare capacity of each node.
+    WeightedRandom,
+    /// Sample two nodes at random and keep the less loaded one.
+    PowerOfTwoChoices,
+}
+
+impl SelectionPolicy {
+    /// Picks one node out of `candidates`. `cursor` is the per-topic state
+    /// used by round-robin, `rng` the randomness of the random policies.
+    ///
+    /// `candidates` is walked more than once instead of being collected, so
+    /// that selecting a node does not allocate on the forwarding path.
+    pub fn select<'a, I, R>(
+        &self,
+        candidates: I,
+        cursor: &AtomicUsize,
+        rng: &mut R,
+    ) -> Option<&'a Node>
+    where
+        I: Iterator<Item = &'a Node> + Clone,
+        R: Rng + ?Sized,
+    {
+        match self {
+            SelectionPolicy::LeastLoaded => candidates.min_by_key(|node| node.load()),
+            SelectionPolicy::RoundRobin => {
+                let n = candidates.clone().count();
+                if n == 0 {
+                    return None;
+                }
+
+                let idx = cursor.fetch_add(1, Ordering::Relaxed) % n;
+                candidates.clone().nth(idx)
+            }
+            SelectionPolicy::WeightedRandom => {
+                // NOTE: +1 so that fully loaded nodes still have a (small) chance
+                //       and the total weight is never zero.
+                let weight = |node: &Node| 100u32.saturating_sub(node.load()) as u64 + 1;
+
+                let total: u64 = candidates.clone().map(weight).sum();
+                if total == 0 {
+                    return None;
+                }
+
+                let mut r = rng.gen_range(0..total);
+                for node in candidates {
+                    let w = weight(node);
+                    if r < w {
+                        return Some(node);
+                    }
+                    r -= w;
+                }
+
+                None
+            }
+            SelectionPolicy::PowerOfTwoChoices => {
+                let n = candidates.clone().count();
+                match n {
+                    0 => None,
+                    1 => candidates.clone().next(),
+                    _ => {
+                        let i = rng.gen_range(0..n);
+                        let mut j = rng.gen_range(0..n - 1);
+                        if j >= i {
+                            j += 1;
+                        }
+
+                        let a = candidates.clone().nth(i)?;
+                        let b = candidates.clone().nth(j)?;
+                        if b.load() < a.load() {
+                            Some(b)
+                        } else {
+                            Some(a)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tempos::endpoint::PeerAddr;
+
+    use crate::registry::Core;
+
+    /// Nodes 1, 2, ... subscribed to topic "t" with the given loads.
+    fn core(loads: &[u32]) -> Core {
+        let mut core = Core::new();
+        for (i, load) in loads.iter().enumerate() {
+            let id = i as u32 + 1;
+            core.add_node(id, PeerAddr::Udp(([127, 0, 0, 1], id as u16).into()), None);
+            core.update_node_load(id, *load);
+            core.subscribe("t", id);
+        }
+        core
+    }
+
+    fn candidates(core: &Core) -> impl Iterator<Item = &Node> + Clone {
+        core.get_topic("t")
+            .into_iter()
+            .flat_map(|topic| topic.nodes.iter())
+            .map(|id| core.get_node(*id).unwrap())
+    }
+
+    /// Ids of the nodes picked by `rounds` selections, seeded.
+    fn picks(policy: SelectionPolicy, core: &Core, rounds: usize) -> Vec<u32> {
+        let cursor = AtomicUsize::new(0);
+        let mut rng = StdRng::seed_from_u64(42);
+        (0..rounds)
+            .map(|_| {
+                policy
+                    .select(candidates(core), &cursor, &mut rng)
+                    .unwrap()
+                    .id
+            })
+            .collect()
+    }
+
+    const POLICIES: [SelectionPolicy; 4] = [
+        SelectionPolicy::LeastLoaded,
+        SelectionPolicy::RoundRobin,
+        SelectionPolicy::WeightedRandom,
+        SelectionPolicy::PowerOfTwoChoices,
+    ];
+
+    #[test]
+    fn no_candidates() {
+        let core = core(&[]);
+        let mut rng = StdRng::seed_from_u64(42);
+        for policy in POLICIES {
+            assert!(policy
+                .select(candidates(&core), &AtomicUsize::new(0), &mut rng)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn single_candidate() {
+        let core = core(&[100]);
+        for policy in POLICIES {
+            assert_eq!(picks(policy, &core, 10), [1; 10], "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn least_loaded() {
+        assert_eq!(
+            picks(SelectionPolicy::LeastLoaded, &core(&[50, 10, 30]), 3),
+            [2, 2, 2]
+        );
+        // NOTE: ties go to the first subscribed node.
+        assert_eq!(
+            picks(SelectionPolicy::LeastLoaded, &core(&[20, 10, 10]), 2),
+            [2, 2]
+        );
+    }
+
+    #[test]
+    fn round_robin() {
+        let core = core(&[90, 0, 50]);
+        assert_eq!(
+            picks(SelectionPolicy::RoundRobin, &core, 7),
+            [1, 2, 3, 1, 2, 3, 1]
+        );
+
+        let cursor = AtomicUsize::new(usize::MAX);
+        let mut rng = StdRng::seed_from_u64(42);
+        let node = SelectionPolicy::RoundRobin.select(candidates(&core), &cursor, &mut rng);
+        assert_eq!(node.unwrap().id, (usize::MAX % 3) as u32 + 1);
+    }
+
+    #[test]
+    fn weighted_random_favours_spare_capacity() {
+        let picks = picks(
+            SelectionPolicy::WeightedRandom,
+            &core(&[100, 0, 50]),
+            10_000,
+        );
+        let count = |id| picks.iter().filter(|&&n| n == id).count();
+
+        // NOTE: weights 1, 101 and 51.
+        assert!((20..=120).contains(&count(1)), "{}", count(1));
+        assert!((6200..=7000).contains(&count(2)), "{}", count(2));
+        assert!((3000..=3700).contains(&count(3)), "{}", count(3));
+        assert_eq!(count(1) + count(2) + count(3), 10_000);
+    }
+
+    #[test]
+    fn power_of_two_choices_never_picks_the_most_loaded() {
+        let picks = picks(
+            SelectionPolicy::PowerOfTwoChoices,
+            &core(&[40, 10, 90, 20]),
+            1_000,
+        );
+        assert!(!picks.contains(&3));
+        for id in [1, 2, 4] {
+            assert!(picks.contains(&id), "node {} never picked", id);
+        }
+
+        // NOTE: out of two nodes both are sampled, the less loaded wins.
+        assert_eq!(picks_of_two(SelectionPolicy::PowerOfTwoChoices), [2; 20]);
+    }
+
+    fn picks_of_two(policy: SelectionPolicy) -> Vec<u32> {
+        picks(policy, &core(&[70, 30]), 20)
+    }
+
+    #[test]
+    fn same_seed_same_picks() {
+        let core = core(&[10, 20, 30, 40, 50]);
+        for policy in [
+            SelectionPolicy::WeightedRandom,
+            SelectionPolicy::PowerOfTwoChoices,
+        ] {
+            assert_eq!(picks(policy, &core, 100), picks(policy, &core, 100));
+        }
+    }
+}
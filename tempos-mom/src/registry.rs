@@ -0,0 +1,616 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tempos::endpoint::PeerAddr;
+use tempos::shm::ShmRing;
+
+use crate::config::{self, Config};
+use crate::policy::SelectionPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipEvent {
+    Joined(u32),
+    Suspected(u32),
+    Recovered(u32),
+    Left(u32),
+    /// No report for longer than `dead_timeout`, the node has to be removed.
+    Expired(u32),
+}
+
+impl MembershipEvent {
+    pub fn log(self) {
+        match self {
+            MembershipEvent::Joined(id) => log::info!("node {} joined", id),
+            MembershipEvent::Suspected(id) => log::warn!("node {} is suspect", id),
+            MembershipEvent::Recovered(id) => log::info!("node {} recovered", id),
+            MembershipEvent::Left(id) => log::info!("node {} left", id),
+            MembershipEvent::Expired(id) => log::warn!("node {} expired", id),
+        }
+    }
+}
+
+/// State of a node updated by its MONITORING reports, shared by every
+/// snapshot of the registry.
+#[derive(Debug)]
+struct NodeStatus {
+    load: AtomicU32,
+    memory: AtomicU32,
+    in_flight: AtomicU32,
+    warm: AtomicBool,
+    /// No report for longer than `suspect_timeout`, the node is not selected.
+    suspect: AtomicBool,
+    /// Nanoseconds since `Core::epoch`.
+    last_seen: AtomicU64,
+}
+
+/// A registered invoker.
+///
+/// The fields updated by MONITORING reports are atomics, so that the
+/// lanes update and select nodes on their snapshot of the [`Core`].
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: u32,
+    /// Topics the node is subscribed to, mirrors `Topic::nodes`.
+    pub topics: HashSet<String>,
+    pub channel: PeerAddr,
+    /// Shared-memory ring of a node on this host, preferred over `channel`.
+    pub ring: Option<Arc<ShmRing>>,
+    status: Arc<NodeStatus>,
+}
+
+impl Node {
+    pub fn load(&self) -> u32 {
+        self.status.load.load(Ordering::Relaxed)
+    }
+
+    pub fn is_suspect(&self) -> bool {
+        self.status.suspect.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Topic {
+    /// Subscribed nodes, without duplicates.
+    pub nodes: Vec<u32>,
+    pub policy: SelectionPolicy,
+    /// Round-robin position, shared by every snapshot.
+    cursor: Arc<AtomicUsize>,
+}
+
+/// Node registry shared by all the quality lanes of the MOM, through a
+/// [`SharedCore`].
+#[derive(Clone)]
+pub struct Core {
+    nodes: HashMap<u32, Node>,
+    /// Number of nodes registered at each channel.
+    channels: HashMap<PeerAddr, usize>,
+    topics: HashMap<String, Topic>,
+    default_policy: SelectionPolicy,
+    /// Nodes reporting a load above this value (0..=100) are not selected.
+    max_load: u32,
+    suspect_timeout: Duration,
+    dead_timeout: Duration,
+    epoch: Instant,
+}
+
+impl Core {
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            channels: HashMap::new(),
+            topics: HashMap::new(),
+            default_policy: SelectionPolicy::default(),
+            max_load: 100,
+            suspect_timeout: Duration::from_millis(config::DEFAULT_SUSPECT_TIMEOUT_MS),
+            dead_timeout: Duration::from_millis(config::DEFAULT_DEAD_TIMEOUT_MS),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let mut core = Self::new();
+        core.default_policy = config.default_policy;
+        core.max_load = (config.max_node_usage * 100.0) as u32;
+        core.suspect_timeout = Duration::from_millis(config.suspect_timeout_ms);
+        core.dead_timeout = Duration::from_millis(config.dead_timeout_ms);
+
+        for topic in &config.topics {
+            core.set_policy(&topic.name, topic.policy);
+        }
+
+        core
+    }
+
+    fn since_epoch(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_nanos() as u64
+    }
+
+    /// Registers `id` at `channel`. A node that is already known keeps its
+    /// subscriptions and only has its address and ring refreshed.
+    pub fn add_node(
+        &mut self,
+        id: u32,
+        channel: PeerAddr,
+        ring: Option<ShmRing>,
+    ) -> Option<MembershipEvent> {
+        let now = self.since_epoch(Instant::now());
+
+        if let Some(node) = self.nodes.get_mut(&id) {
+            log::debug!("refreshing node {} at {}", id, channel);
+            if node.channel != channel {
+                let old = std::mem::replace(&mut node.channel, channel.clone());
+                Self::release_channel(&mut self.channels, &old);
+                *self.channels.entry(channel).or_default() += 1;
+            }
+            node.ring = ring.map(Arc::new);
+            node.status.suspect.store(false, Ordering::Relaxed);
+            node.status.last_seen.store(now, Ordering::Relaxed);
+            return None;
+        }
+
+        log::debug!("adding node {} at {}", id, channel);
+        *self.channels.entry(channel.clone()).or_default() += 1;
+        let node = Node {
+            id,
+            topics: HashSet::new(),
+            channel,
+            ring: ring.map(Arc::new),
+            status: Arc::new(NodeStatus {
+                load: AtomicU32::new(0),
+                memory: AtomicU32::new(0),
+                in_flight: AtomicU32::new(0),
+                warm: AtomicBool::new(false),
+                suspect: AtomicBool::new(false),
+                last_seen: AtomicU64::new(now),
+            }),
+        };
+        self.nodes.insert(id, node);
+
+        Some(MembershipEvent::Joined(id))
+    }
+
+    pub fn remove_node(&mut self, id: u32) -> Option<MembershipEvent> {
+        let node = self.nodes.remove(&id)?;
+        Self::release_channel(&mut self.channels, &node.channel);
+        for name in &node.topics {
+            if let Some(topic) = self.topics.get_mut(name) {
+                topic.nodes.retain(|n| *n != id);
+            }
+        }
+
+        Some(MembershipEvent::Left(id))
+    }
+
+    fn release_channel(channels: &mut HashMap<PeerAddr, usize>, channel: &PeerAddr) {
+        if let Some(count) = channels.get_mut(channel) {
+            *count -= 1;
+            if *count == 0 {
+                channels.remove(channel);
+            }
+        }
+    }
+
+    /// Whether a node is registered at `channel`.
+    pub fn has_channel(&self, channel: &PeerAddr) -> bool {
+        self.channels.contains_key(channel)
+    }
+
+    pub fn update_node_load(&self, id: u32, load: u32) {
+        if let Some(node) = self.nodes.get(&id) {
+            node.status.load.store(load, Ordering::Relaxed);
+        }
+    }
+
+    pub fn update_node_status(&self, id: u32, memory: u32, in_flight: u32, warm: bool) {
+        if let Some(node) = self.nodes.get(&id) {
+            node.status.memory.store(memory, Ordering::Relaxed);
+            node.status.in_flight.store(in_flight, Ordering::Relaxed);
+            node.status.warm.store(warm, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that `id` is alive. Returns an event if the node was suspected.
+    pub fn touch_node(&self, id: u32, now: Instant) -> Option<MembershipEvent> {
+        let status = &self.nodes.get(&id)?.status;
+        status
+            .last_seen
+            .store(self.since_epoch(now), Ordering::Relaxed);
+
+        if status.suspect.swap(false, Ordering::Relaxed) {
+            return Some(MembershipEvent::Recovered(id));
+        }
+
+        None
+    }
+
+    /// Removes `id` if it is still silent for longer than `dead_timeout`,
+    /// e.g. after an [`MembershipEvent::Expired`] reported on an older
+    /// snapshot.
+    pub fn remove_expired(&mut self, id: u32, now: Instant) -> Option<MembershipEvent> {
+        let now = self.since_epoch(now);
+        let last_seen = self
+            .nodes
+            .get(&id)?
+            .status
+            .last_seen
+            .load(Ordering::Relaxed);
+        if now.saturating_sub(last_seen) < self.dead_timeout.as_nanos() as u64 {
+            return None;
+        }
+
+        self.remove_node(id)
+    }
+
+    /// Marks nodes silent for longer than `suspect_timeout` as suspect and
+    /// reports those silent for longer than `dead_timeout` as expired. Expired
+    /// nodes still have to be removed with [`Core::remove_expired`].
+    pub fn check_nodes(&self, now: Instant) -> Vec<MembershipEvent> {
+        let now = self.since_epoch(now);
+        let suspect_timeout = self.suspect_timeout.as_nanos() as u64;
+        let dead_timeout = self.dead_timeout.as_nanos() as u64;
+
+        let mut events = vec![];
+        for node in self.nodes.values() {
+            let status = &node.status;
+            let silence = now.saturating_sub(status.last_seen.load(Ordering::Relaxed));
+            if silence >= dead_timeout {
+                events.push(MembershipEvent::Expired(node.id));
+            } else if silence >= suspect_timeout && !status.suspect.swap(true, Ordering::Relaxed) {
+                events.push(MembershipEvent::Suspected(node.id));
+            }
+        }
+
+        events
+    }
+
+    pub fn set_policy(&mut self, topic: &str, policy: SelectionPolicy) {
+        self.topic_entry(topic).policy = policy;
+    }
+
+    /// Subscribes the registered node `id` to `topic`. Returns false if the
+    /// node is unknown or already subscribed.
+    pub fn subscribe(&mut self, topic: &str, id: u32) -> bool {
+        let added = match self.nodes.get_mut(&id) {
+            Some(node) => node.topics.insert(topic.to_string()),
+            None => false,
+        };
+        if !added {
+            return false;
+        }
+
+        self.topic_entry(topic).nodes.push(id);
+        true
+    }
+
+    /// Removes the subscription of `id` to `topic`. Returns false if there
+    /// was none.
+    pub fn unsubscribe(&mut self, topic: &str, id: u32) -> bool {
+        let removed = match self.nodes.get_mut(&id) {
+            Some(node) => node.topics.remove(topic),
+            None => false,
+        };
+        if !removed {
+            return false;
+        }
+
+        if let Some(topic) = self.topics.get_mut(topic) {
+            topic.nodes.retain(|n| *n != id);
+        }
+        true
+    }
+
+    fn topic_entry(&mut self, topic: &str) -> &mut Topic {
+        let default_policy = self.default_policy;
+        self.topics
+            .entry(topic.to_string())
+            .or_insert_with(|| Topic {
+                policy: default_policy,
+                ..Default::default()
+            })
+    }
+
+    pub fn get_topic(&self, topic: &str) -> Option<&Topic> {
+        self.topics.get(topic)
+    }
+
+    #[cfg(test)]
+    pub fn get_node(&self, id: u32) -> Option<&Node> {
+        self.nodes.get(&id)
+    }
+
+    /// Picks the node that should receive the next INVOK for `topic`
+    /// according to the topic's selection policy.
+    pub fn select_node(&self, topic: &str) -> Option<&Node> {
+        let topic = self.topics.get(topic)?;
+        let max_load = self.max_load;
+        let nodes = &self.nodes;
+
+        let candidates = topic
+            .nodes
+            .iter()
+            .filter_map(|id| nodes.get(id))
+            .filter(|node| !node.is_suspect() && node.load() <= max_load);
+
+        topic
+            .policy
+            .select(candidates, &topic.cursor, &mut rand::thread_rng())
+    }
+}
+
+/// The registry of the MOM: readers work on an immutable snapshot of the
+/// [`Core`], writers publish a modified copy of the latest one.
+///
+/// Registrations are rare next to INVOKs, so the lanes keep their snapshot
+/// in a [`CoreReader`] and only take the lock after a publication.
+pub struct SharedCore {
+    /// Latest snapshot, the lock also serializes the writers.
+    current: Mutex<Arc<Core>>,
+    /// Bumped on every publication.
+    generation: AtomicU64,
+}
+
+impl SharedCore {
+    pub fn new(core: Core) -> Self {
+        Self {
+            current: Mutex::new(Arc::new(core)),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Core> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Applies `f` to a copy of the latest snapshot and publishes it.
+    pub fn update<R>(&self, f: impl FnOnce(&mut Core) -> R) -> R {
+        let mut current = self.current.lock().unwrap();
+        let mut core = Core::clone(&current);
+        let res = f(&mut core);
+        *current = Arc::new(core);
+        self.generation.fetch_add(1, Ordering::Release);
+
+        res
+    }
+}
+
+/// Snapshot of a [`SharedCore`] kept by a lane.
+pub struct CoreReader {
+    shared: Arc<SharedCore>,
+    generation: u64,
+    snapshot: Arc<Core>,
+}
+
+impl CoreReader {
+    pub fn new(shared: Arc<SharedCore>) -> Self {
+        Self {
+            generation: shared.generation.load(Ordering::Acquire),
+            snapshot: shared.load(),
+            shared,
+        }
+    }
+
+    /// The latest snapshot, without locking unless a newer one was published.
+    pub fn get(&mut self) -> &Core {
+        // NOTE: the generation is read before the snapshot, which is at
+        //       least as recent.
+        let generation = self.shared.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            self.snapshot = self.shared.load();
+            self.generation = generation;
+        }
+
+        &self.snapshot
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut Core) -> R) -> R {
+        self.shared.update(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> PeerAddr {
+        PeerAddr::Udp(([127, 0, 0, 1], port).into())
+    }
+
+    fn nodes(core: &Core, topic: &str) -> Vec<u32> {
+        let mut nodes = core
+            .get_topic(topic)
+            .map_or_else(Vec::new, |topic| topic.nodes.clone());
+        nodes.sort_unstable();
+        nodes
+    }
+
+    fn topics(core: &Core, id: u32) -> Vec<String> {
+        let mut topics: Vec<_> = core.nodes[&id].topics.iter().cloned().collect();
+        topics.sort_unstable();
+        topics
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let mut core = Core::new();
+        assert_eq!(
+            core.add_node(1, addr(1), None),
+            Some(MembershipEvent::Joined(1))
+        );
+        assert_eq!(
+            core.add_node(2, addr(2), None),
+            Some(MembershipEvent::Joined(2))
+        );
+
+        assert!(core.subscribe("a", 1));
+        assert!(core.subscribe("a", 2));
+        assert!(core.subscribe("b", 1));
+        assert!(!core.subscribe("a", 1));
+        assert!(!core.subscribe("a", 3));
+        assert_eq!(nodes(&core, "a"), [1, 2]);
+        assert_eq!(nodes(&core, "b"), [1]);
+        assert_eq!(topics(&core, 1), ["a", "b"]);
+
+        assert!(core.unsubscribe("a", 1));
+        assert!(!core.unsubscribe("a", 1));
+        assert!(!core.unsubscribe("c", 2));
+        assert_eq!(nodes(&core, "a"), [2]);
+        assert_eq!(nodes(&core, "b"), [1]);
+        assert_eq!(topics(&core, 1), ["b"]);
+    }
+
+    #[test]
+    fn remove_node_leaves_its_topics() {
+        let mut core = Core::new();
+        core.add_node(1, addr(1), None);
+        core.add_node(2, addr(2), None);
+        core.subscribe("a", 1);
+        core.subscribe("a", 2);
+        core.subscribe("b", 1);
+
+        assert_eq!(core.remove_node(1), Some(MembershipEvent::Left(1)));
+        assert_eq!(core.remove_node(1), None);
+        assert_eq!(nodes(&core, "a"), [2]);
+        assert!(nodes(&core, "b").is_empty());
+        assert!(!core.has_channel(&addr(1)));
+        assert!(core.has_channel(&addr(2)));
+        assert!(!core.subscribe("a", 1));
+        assert_eq!(core.select_node("b").map(|node| node.id), None);
+    }
+
+    #[test]
+    fn reregistration_keeps_subscriptions() {
+        let mut core = Core::new();
+        core.add_node(1, addr(1), None);
+        core.subscribe("a", 1);
+
+        assert_eq!(core.add_node(1, addr(10), None), None);
+        assert!(!core.subscribe("a", 1));
+        assert_eq!(nodes(&core, "a"), [1]);
+        assert_eq!(topics(&core, 1), ["a"]);
+        assert_eq!(core.nodes[&1].channel, addr(10));
+        assert!(core.has_channel(&addr(10)));
+        assert!(!core.has_channel(&addr(1)));
+        assert_eq!(core.select_node("a").map(|node| node.id), Some(1));
+    }
+
+    #[test]
+    fn shared_channel_outlives_one_node() {
+        let mut core = Core::new();
+        core.add_node(1, addr(1), None);
+        core.add_node(2, addr(1), None);
+
+        core.remove_node(1);
+        assert!(core.has_channel(&addr(1)));
+        core.add_node(2, addr(2), None);
+        assert!(!core.has_channel(&addr(1)));
+        assert!(core.has_channel(&addr(2)));
+        core.remove_node(2);
+        assert!(core.channels.is_empty());
+    }
+
+    #[test]
+    fn expired_nodes_are_removed_unless_seen_since() {
+        let mut core = Core::new();
+        let start = Instant::now();
+        core.add_node(1, addr(1), None);
+        core.add_node(2, addr(2), None);
+        core.subscribe("a", 1);
+        core.subscribe("a", 2);
+
+        let later = start + core.dead_timeout * 2;
+        let mut events = core.check_nodes(later);
+        events.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(
+            events,
+            [MembershipEvent::Expired(1), MembershipEvent::Expired(2)]
+        );
+
+        // NOTE: node 2 reported between the check and the removal.
+        core.touch_node(2, later);
+        assert_eq!(
+            core.remove_expired(1, later),
+            Some(MembershipEvent::Left(1))
+        );
+        assert_eq!(core.remove_expired(2, later), None);
+        assert_eq!(core.remove_expired(3, later), None);
+        assert_eq!(nodes(&core, "a"), [2]);
+    }
+
+    #[test]
+    fn saturated_and_suspect_nodes_are_not_selected() {
+        for policy in [
+            SelectionPolicy::LeastLoaded,
+            SelectionPolicy::RoundRobin,
+            SelectionPolicy::WeightedRandom,
+            SelectionPolicy::PowerOfTwoChoices,
+        ] {
+            let mut core = Core::from_config(&Config {
+                max_node_usage: 0.5,
+                default_policy: policy,
+                ..Config::default()
+            });
+            let start = Instant::now();
+            for (id, load) in [(1, 90), (2, 50), (3, 0), (4, 10)] {
+                core.add_node(id, addr(id as u16), None);
+                core.update_node_load(id, load);
+                core.subscribe("a", id);
+            }
+
+            // NOTE: node 3 is the least loaded but stopped reporting.
+            let later = start + core.suspect_timeout * 2;
+            for id in [1, 2, 4] {
+                core.touch_node(id, later);
+            }
+            assert_eq!(core.check_nodes(later), [MembershipEvent::Suspected(3)]);
+
+            let mut picked: Vec<u32> = (0..200)
+                .map(|_| core.select_node("a").unwrap().id)
+                .collect();
+            picked.sort_unstable();
+            picked.dedup();
+            // NOTE: out of two candidates, power of two choices compares both.
+            match policy {
+                SelectionPolicy::LeastLoaded | SelectionPolicy::PowerOfTwoChoices => {
+                    assert_eq!(picked, [4], "{:?}", policy)
+                }
+                _ => assert_eq!(picked, [2, 4], "{:?}", policy),
+            }
+
+            core.update_node_load(2, 51);
+            core.update_node_load(4, 100);
+            assert!(core.select_node("a").is_none(), "{:?}", policy);
+
+            core.touch_node(3, later);
+            assert_eq!(core.select_node("a").unwrap().id, 3, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn readers_follow_the_published_snapshots() {
+        let shared = Arc::new(SharedCore::new(Core::new()));
+        let mut reader = CoreReader::new(shared.clone());
+        let old = shared.load();
+
+        shared.update(|core| {
+            core.add_node(1, addr(1), None);
+            core.subscribe("a", 1)
+        });
+        assert!(old.get_topic("a").is_none());
+        assert_eq!(nodes(reader.get(), "a"), [1]);
+
+        // NOTE: the status of a node is shared with the older snapshots.
+        let snapshot = shared.load();
+        shared.update(|core| core.subscribe("b", 1));
+        snapshot.update_node_load(1, 42);
+        assert_eq!(reader.get().select_node("b").unwrap().load(), 42);
+
+        assert_eq!(
+            reader.update(|core| core.remove_node(1)),
+            Some(MembershipEvent::Left(1))
+        );
+        assert!(reader.get().select_node("a").is_none());
+        assert_eq!(nodes(&snapshot, "a"), [1]);
+    }
+}
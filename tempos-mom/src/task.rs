@@ -1 +1,290 @@
-pub struct MomTask {}
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tempos::buffer::{Buffer, BufferPool};
+use tempos::endpoint::PeerAddr;
+use tempos::message::{decode, TemposMessage, MAX_MESSAGE_LEN};
+
+use crate::chains::{Chains, Route};
+use crate::config::MomTaskConfig;
+use crate::registry::{CoreReader, SharedCore};
+use crate::transport::{Registry, Role, TransportAdapter, Uri};
+
+/// Where a message read by a task is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NextHop {
+    /// The target of `out_endpoint`.
+    Out,
+    /// The node selected for an INVOK.
+    Node(PeerAddr),
+}
+
+/// A forwarding pipeline: the TEMPOS messages read from `in_endpoint` are
+/// sent with the task's `SO_PRIORITY`, so that each task maps to its own
+/// taprio traffic class.
+///
+/// INVOKs are routed like on the lanes, through the chains and the node
+/// registry: they are stamped with their chain id and hop, and go to the
+/// node selected for their topic, through its ring or, if the egress
+/// transport can address it, its channel. Otherwise they are written to
+/// `out_endpoint`, like the other messages.
+pub struct MomTask {
+    id: u8,
+    ingress: Box<dyn TransportAdapter>,
+    egress: Box<dyn TransportAdapter>,
+    out_uri: Uri,
+    core: CoreReader,
+    chains: Arc<Chains>,
+    /// Chained INVOKs are stamped here before replacing the received one.
+    scratch: Buffer,
+    dropped: u64,
+}
+
+impl MomTask {
+    pub fn new(
+        config: &MomTaskConfig,
+        transports: &Registry,
+        core: Arc<SharedCore>,
+        chains: Arc<Chains>,
+    ) -> anyhow::Result<Self> {
+        let in_uri: Uri = config.in_endpoint.parse()?;
+        let mut out_uri: Uri = config.out_endpoint.parse()?;
+
+        // NOTE: parameters set in the URI take precedence over the task's.
+        out_uri
+            .params
+            .entry("priority".to_string())
+            .or_insert_with(|| config.priority.to_string());
+        if let Some(iface) = &config.iface {
+            out_uri
+                .params
+                .entry("iface".to_string())
+                .or_insert_with(|| iface.clone());
+        }
+
+        let ingress = transports.open(&in_uri, Role::Ingress)?;
+        let egress = transports.open(&out_uri, Role::Egress)?;
+
+        if config.priority != 0 && !egress.capabilities().priority {
+            log::warn!(
+                "task {}: {} ignores the socket priority",
+                config.id,
+                out_uri
+            );
+        }
+
+        Ok(Self {
+            id: config.id,
+            ingress,
+            egress,
+            out_uri,
+            core: CoreReader::new(core),
+            chains,
+            scratch: Buffer::with_capacity(MAX_MESSAGE_LEN, 0),
+            dropped: 0,
+        })
+    }
+
+    pub fn run(&mut self, pool: &BufferPool, r: Arc<AtomicBool>) -> anyhow::Result<()> {
+        let mut buf = pool
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("no receive buffer left for task {}", self.id))?;
+        // NOTE: TAP devices carry Ethernet frames, not TEMPOS messages.
+        let raw = self.ingress.capabilities().raw;
+
+        while r.load(Ordering::Relaxed) {
+            buf.clear();
+            let bytes_read = match self.ingress.read(buf.spare_mut()) {
+                Ok(bytes_read) => bytes_read,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.dropped += 1;
+                    log::warn!("task {}: {} [{} dropped]", self.id, e, self.dropped);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            buf.commit(bytes_read);
+
+            let next = if raw {
+                NextHop::Out
+            } else {
+                match self.next_hop(&mut buf) {
+                    Some(next) => next,
+                    None => continue,
+                }
+            };
+
+            let res = match &next {
+                NextHop::Out => self.egress.write(buf.as_slice()),
+                NextHop::Node(peer) => self.egress.write_to(buf.as_slice(), peer),
+            };
+            if let Err(e) = res {
+                log::error!(
+                    "task {}: error forwarding to {}: {}",
+                    self.id,
+                    match &next {
+                        NextHop::Out => self.out_uri.to_string(),
+                        NextHop::Node(peer) => peer.to_string(),
+                    },
+                    e
+                );
+            }
+        }
+
+        self.ingress.close()?;
+        self.egress.close()?;
+
+        Ok(())
+    }
+
+    /// Returns where `buf` has to be written, `None` if it was dropped or
+    /// handed over through the ring of its node. Malformed messages and
+    /// INVOKs past their deadline, for a finished or unknown chain or
+    /// without an available node are dropped, chained INVOKs are restamped
+    /// in `buf` first.
+    fn next_hop(&mut self, buf: &mut Buffer) -> Option<NextHop> {
+        let (header, msg) = match decode(buf.as_slice()) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.dropped += 1;
+                log::warn!(
+                    "task {}: dropping malformed message: {} [{} dropped]",
+                    self.id,
+                    e,
+                    self.dropped
+                );
+                return None;
+            }
+        };
+
+        let (seq, chain, hop, topic, data) = match msg {
+            TemposMessage::Invok {
+                seq,
+                chain,
+                hop,
+                topic,
+                data,
+            } => (seq, chain, hop, topic, data),
+            _ => return Some(NextHop::Out),
+        };
+
+        if header.is_expired(tempos::now_ns()) {
+            self.dropped += 1;
+            log::warn!(
+                "task {}: dropping INVOK {}, deadline missed [{} dropped]",
+                self.id,
+                seq,
+                self.dropped
+            );
+            return None;
+        }
+
+        let mut restamped = false;
+        let topic = match self.chains.route(chain, hop, topic) {
+            Route::Topic => topic,
+            Route::Hop {
+                chain: id,
+                hop: index,
+                topic: next,
+            } => {
+                if (id, index, next) != (chain, hop, topic) {
+                    self.scratch.clear();
+                    let stamped = TemposMessage::Invok {
+                        seq,
+                        chain: id,
+                        hop: index,
+                        topic: next,
+                        data,
+                    }
+                    .encode_into(&header, &mut self.scratch);
+                    if let Err(e) = stamped {
+                        self.dropped += 1;
+                        log::warn!(
+                            "task {}: dropping INVOK {} for hop {} of chain {}: {} [{} dropped]",
+                            self.id,
+                            seq,
+                            index,
+                            id,
+                            e,
+                            self.dropped
+                        );
+                        return None;
+                    }
+                    restamped = true;
+                }
+                next
+            }
+            Route::Completed { chain, name } => {
+                log::debug!(
+                    "task {}: INVOK {} completed chain {} ({})",
+                    self.id,
+                    seq,
+                    chain,
+                    name
+                );
+                return None;
+            }
+            Route::Unknown { chain } => {
+                self.dropped += 1;
+                log::warn!(
+                    "task {}: dropping INVOK {} for unknown chain {} [{} dropped]",
+                    self.id,
+                    seq,
+                    chain,
+                    self.dropped
+                );
+                return None;
+            }
+        };
+
+        let node = match self.core.get().select_node(topic) {
+            Some(node) => node,
+            None => {
+                log::warn!("task {}: no available node for topic '{}'", self.id, topic);
+                return None;
+            }
+        };
+
+        if restamped && buf.set_data(self.scratch.as_slice()).is_err() {
+            self.dropped += 1;
+            log::warn!(
+                "task {}: dropping INVOK {}, stamped message larger than the receive buffer [{} dropped]",
+                self.id,
+                seq,
+                self.dropped
+            );
+            return None;
+        }
+
+        if let Some(ring) = &node.ring {
+            match ring.push(buf.as_slice()) {
+                Ok(()) => return None,
+                Err(e) => log::debug!(
+                    "task {}: ring of node {}: {}, falling back to {}",
+                    self.id,
+                    node.id,
+                    e,
+                    node.channel
+                ),
+            }
+        }
+
+        if self.egress.capabilities().addressed {
+            Some(NextHop::Node(node.channel.clone()))
+        } else {
+            Some(NextHop::Out)
+        }
+    }
+}
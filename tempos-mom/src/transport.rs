@@ -0,0 +1,255 @@
+//! Pluggable ingress/egress transports for MOM tasks, selected by URI:
+//!
+//! ```text
+//! udp://127.0.0.1:3333?priority=3&iface=eth0
+//! unix:///run/tempos/mom.sock
+//! unix+seqpacket:///run/tempos/task0.sock
+//! tcp://10.0.0.2:4000
+//! tap://tap0
+//! ```
+//!
+//! The URIs are parsed by [`tempos::uri`], like the addresses of the lanes.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use tempos::endpoint::PeerAddr;
+
+pub use tempos::uri::Uri;
+
+mod seqpacket;
+mod tap;
+mod tcp;
+mod udp;
+mod unix;
+
+/// Read timeout of the ingress adapters, so that tasks can check whether
+/// they have to stop.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Whether `e` only means that nothing arrived within [`READ_TIMEOUT`].
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Bind or listen on the URI target and read from it.
+    Ingress,
+    /// Send to the URI target.
+    Egress,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Message boundaries are preserved by the transport itself.
+    pub datagram: bool,
+    /// The `priority` parameter (SO_PRIORITY) is honoured.
+    pub priority: bool,
+    /// The adapter carries raw L2 frames instead of TEMPOS messages.
+    pub raw: bool,
+    /// Messages can be sent to other peers than the URI target, see
+    /// [`TransportAdapter::write_to`].
+    pub addressed: bool,
+}
+
+pub trait TransportAdapter: Send {
+    /// Receives one message into `buf`. Fails with `WouldBlock` or
+    /// `TimedOut` when nothing arrived within [`READ_TIMEOUT`].
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Sends one message.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Sends one message to `peer` instead of the URI target.
+    fn write_to(&mut self, _buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot address {}", peer),
+        ))
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities;
+}
+
+pub type AdapterFactory = fn(&Uri, Role) -> anyhow::Result<Box<dyn TransportAdapter>>;
+
+/// Maps URI schemes to the adapters implementing them.
+pub struct Registry {
+    factories: HashMap<String, AdapterFactory>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, scheme: &str, factory: AdapterFactory) {
+        self.factories.insert(scheme.to_ascii_lowercase(), factory);
+    }
+
+    pub fn supports(&self, scheme: &str) -> bool {
+        self.factories.contains_key(scheme)
+    }
+
+    pub fn open(&self, uri: &Uri, role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+        let factory = self
+            .factories
+            .get(&uri.scheme)
+            .ok_or_else(|| anyhow::anyhow!("{}: unsupported transport '{}'", uri, uri.scheme))?;
+
+        factory(uri, role)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register("udp", udp::open);
+        registry.register("unix", unix::open);
+        registry.register("unix+seqpacket", seqpacket::open);
+        registry.register("tcp", tcp::open);
+        registry.register("tap", tap::open);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+    use std::path::PathBuf;
+
+    fn free_udp_port() -> u16 {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.local_addr().unwrap().port()
+    }
+
+    fn free_tcp_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tempos-mom-{}-{}.sock", name, std::process::id()))
+    }
+
+    fn open(uri: &str, role: Role) -> Box<dyn TransportAdapter> {
+        Registry::default()
+            .open(&uri.parse().unwrap(), role)
+            .unwrap()
+    }
+
+    /// Reads until a message arrives, through the timeouts and accepts.
+    fn read_message(ingress: &mut dyn TransportAdapter) -> Vec<u8> {
+        let mut buf = [0u8; 256];
+        for _ in 0..50 {
+            match ingress.read(&mut buf) {
+                Ok(n) => return buf[..n].to_vec(),
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+
+        panic!("no message received");
+    }
+
+    fn round_trip(uri: &str) {
+        let mut ingress = open(uri, Role::Ingress);
+        let mut egress = open(uri, Role::Egress);
+
+        egress.write(b"first").unwrap();
+        egress.write(b"second").unwrap();
+        assert_eq!(read_message(&mut *ingress), b"first");
+        assert_eq!(read_message(&mut *ingress), b"second");
+
+        egress.close().unwrap();
+        ingress.close().unwrap();
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        round_trip(&format!("udp://127.0.0.1:{}?priority=0", free_udp_port()));
+    }
+
+    #[test]
+    fn udp_writes_to_other_peers() {
+        let port = free_udp_port();
+        let mut ingress = open(&format!("udp://127.0.0.1:{}", port), Role::Ingress);
+        let mut egress = open(
+            &format!("udp://127.0.0.1:{}", free_udp_port()),
+            Role::Egress,
+        );
+        assert!(egress.capabilities().addressed);
+
+        let peer = PeerAddr::Udp(SocketAddr::from(([127, 0, 0, 1], port)));
+        egress.write_to(b"routed", &peer).unwrap();
+        assert_eq!(read_message(&mut *ingress), b"routed");
+
+        let unix = PeerAddr::Unix(socket_path("unreachable"));
+        let err = egress.write_to(b"routed", &unix).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        let path = socket_path("unix");
+        round_trip(&format!("unix://{}", path.display()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn seqpacket_round_trip() {
+        let path = socket_path("seqpacket");
+        round_trip(&format!("unix+seqpacket://{}", path.display()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        round_trip(&format!("tcp://127.0.0.1:{}", free_tcp_port()));
+    }
+
+    #[test]
+    fn tcp_accepts_a_new_client_after_a_reset() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_tcp_port()));
+        let mut ingress = open(&format!("tcp://{}", addr), Role::Ingress);
+        let mut buf = [0u8; 256];
+
+        // NOTE: a partial frame is pending when the client resets.
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[0, 0, 0, 8, 1, 2]).unwrap();
+        assert!(ingress.read(&mut buf).is_err_and(|e| is_timeout(&e)));
+        socket2::SockRef::from(&client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(client);
+        std::thread::sleep(Duration::from_millis(10));
+        let err = ingress.read(&mut buf).unwrap_err();
+        assert!(is_timeout(&err), "{}", err);
+
+        let mut egress = open(&format!("tcp://{}", addr), Role::Egress);
+        egress.write(b"after reset").unwrap();
+        assert_eq!(read_message(&mut *ingress), b"after reset");
+    }
+
+    #[test]
+    fn unknown_scheme() {
+        let uri: Uri = "sctp://10.0.0.2:4000".parse().unwrap();
+        assert_eq!(uri.scheme, "sctp");
+        assert!(!Registry::default().supports(&uri.scheme));
+        assert!(Registry::default().open(&uri, Role::Egress).is_err());
+    }
+}
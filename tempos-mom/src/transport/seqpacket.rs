@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+use tempos::endpoint::{seqpacket_connect, seqpacket_listen};
+
+use super::{is_timeout, Capabilities, Role, TransportAdapter, Uri, READ_TIMEOUT};
+
+/// `unix+seqpacket:///path/to/socket`, a Unix `SOCK_SEQPACKET` socket.
+///
+/// Like TCP the ingress side serves one connection at a time and the egress
+/// side (re)connects lazily, but message boundaries are kept by the kernel.
+pub struct SeqpacketAdapter {
+    listener: Option<UnixListener>,
+    stream: Option<UnixStream>,
+    path: PathBuf,
+}
+
+pub fn open(uri: &Uri, role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+    let path = PathBuf::from(&uri.target);
+
+    let listener = match role {
+        Role::Ingress => {
+            let listener = seqpacket_listen(&path)?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+        Role::Egress => None,
+    };
+
+    Ok(Box::new(SeqpacketAdapter {
+        listener,
+        stream: None,
+        path,
+    }))
+}
+
+impl TransportAdapter for SeqpacketAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.stream.is_none() {
+            let listener = self.listener.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "egress adapter cannot read")
+            })?;
+
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    log::debug!("accepted connection on {}", self.path.display());
+                    stream.set_nonblocking(false)?;
+                    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                    self.stream = Some(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(READ_TIMEOUT);
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let stream = self.stream.as_mut().unwrap();
+        match stream.read(buf) {
+            Ok(0) => {
+                log::debug!("connection on {} closed by the peer", self.path.display());
+                self.stream = None;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(e) if !is_timeout(&e) => {
+                log::warn!("connection on {} lost: {}", self.path.display(), e);
+                self.stream = None;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            res => res,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.listener.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ingress adapter cannot write",
+            ));
+        }
+
+        if self.stream.is_none() {
+            self.stream = Some(seqpacket_connect(&self.path)?);
+        }
+
+        let res = self.stream.as_mut().unwrap().write(buf);
+        match res {
+            Ok(n) if n == buf.len() => Ok(()),
+            Ok(_) => Err(io::ErrorKind::WriteZero.into()),
+            Err(e) => {
+                // NOTE: reconnect on the next write.
+                self.stream = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both)?;
+        }
+        if self.listener.take().is_some() {
+            std::fs::remove_file(&self.path)?;
+        }
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            datagram: true,
+            priority: false,
+            raw: false,
+            addressed: false,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use super::{Capabilities, Role, TransportAdapter, Uri, READ_TIMEOUT};
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// `struct ifreq` restricted to the name and flags used by TUNSETIFF.
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// `tap://tap0`, raw Ethernet frames through a TAP device. Both roles attach
+/// to the same device.
+pub struct TapAdapter {
+    dev: File,
+}
+
+pub fn open(uri: &Uri, _role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+    let name = uri.target.as_bytes();
+    if name.len() >= libc::IFNAMSIZ {
+        anyhow::bail!("{}: interface name too long", uri);
+    }
+
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short,
+        _pad: [0; 22],
+    };
+    for (dst, src) in req.name.iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+
+    let ret = unsafe { libc::ioctl(dev.as_raw_fd(), TUNSETIFF, &mut req) };
+    if ret < 0 {
+        return Err(anyhow::anyhow!(
+            "{}: TUNSETIFF failed: {}",
+            uri,
+            io::Error::last_os_error()
+        ));
+    }
+
+    Ok(Box::new(TapAdapter { dev }))
+}
+
+impl TransportAdapter for TapAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pfd = libc::pollfd {
+            fd: self.dev.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let ret = unsafe { libc::poll(&mut pfd, 1, READ_TIMEOUT.as_millis() as libc::c_int) };
+        match ret {
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => self.dev.read(buf),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.dev.write_all(buf)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            datagram: true,
+            priority: false,
+            raw: true,
+            addressed: false,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+
+use super::{is_timeout, Capabilities, Role, TransportAdapter, Uri, READ_TIMEOUT};
+
+/// `tcp://host:port`. Messages are framed with a big-endian u32 length.
+///
+/// The ingress side serves one connection at a time, the egress side
+/// (re)connects lazily on write.
+pub struct TcpAdapter {
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    addr: SocketAddr,
+    priority: Option<i32>,
+    /// Bytes received but not yet returned as a complete frame.
+    pending: Vec<u8>,
+}
+
+pub fn open(uri: &Uri, role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+    let addr: SocketAddr = uri
+        .target
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{}: invalid address: {}", uri, e))?;
+    let priority: Option<i32> = uri
+        .param("priority")
+        .map_err(|e| anyhow::anyhow!("{}: {}", uri, e))?;
+
+    let listener = match role {
+        Role::Ingress => {
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+        Role::Egress => None,
+    };
+
+    Ok(Box::new(TcpAdapter {
+        listener,
+        stream: None,
+        addr,
+        priority,
+        pending: Vec::with_capacity(4096),
+    }))
+}
+
+impl TcpAdapter {
+    fn accept(&mut self, listener: &TcpListener) -> io::Result<()> {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                log::debug!("accepted TCP connection from {}", peer);
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                self.pending.clear();
+                self.stream = Some(stream);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(READ_TIMEOUT);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves the first complete frame of `pending` into `buf`.
+    fn take_frame(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if self.pending.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([
+            self.pending[0],
+            self.pending[1],
+            self.pending[2],
+            self.pending[3],
+        ]) as usize;
+        if len > buf.len() {
+            self.stream = None;
+            self.pending.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds the {} bytes buffer",
+                    len,
+                    buf.len()
+                ),
+            ));
+        }
+        if self.pending.len() < 4 + len {
+            return Ok(None);
+        }
+
+        buf[..len].copy_from_slice(&self.pending[4..4 + len]);
+        self.pending.drain(..4 + len);
+
+        Ok(Some(len))
+    }
+}
+
+impl TransportAdapter for TcpAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(len) = self.take_frame(buf)? {
+                return Ok(len);
+            }
+
+            if self.stream.is_none() {
+                let listener = self.listener.take().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Unsupported, "egress adapter cannot read")
+                })?;
+                let accepted = self.accept(&listener);
+                self.listener = Some(listener);
+                accepted?;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let stream = self.stream.as_mut().unwrap();
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    log::debug!("TCP connection closed by the peer");
+                    self.stream = None;
+                    self.pending.clear();
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(e) if is_timeout(&e) => return Err(e),
+                Err(e) => {
+                    // NOTE: a reset connection must not stop the task, the
+                    //       next client is accepted instead.
+                    log::warn!("TCP connection lost: {}", e);
+                    self.stream = None;
+                    self.pending.clear();
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.listener.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ingress adapter cannot write",
+            ));
+        }
+
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(self.addr)?;
+            stream.set_nodelay(true)?;
+            if let Some(priority) = self.priority {
+                tempos::set_priority(&stream, priority)?;
+            }
+            self.stream = Some(stream);
+        }
+
+        let stream = self.stream.as_mut().unwrap();
+        let res = stream
+            .write_all(&(buf.len() as u32).to_be_bytes())
+            .and_then(|_| stream.write_all(buf));
+        if res.is_err() {
+            // NOTE: reconnect on the next write.
+            self.stream = None;
+        }
+
+        res
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both)?;
+        }
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            datagram: false,
+            priority: true,
+            raw: false,
+            addressed: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(pending: &[u8]) -> TcpAdapter {
+        TcpAdapter {
+            listener: None,
+            stream: None,
+            addr: ([127, 0, 0, 1], 0).into(),
+            priority: None,
+            pending: pending.to_vec(),
+        }
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn partial_frame_waits_for_the_rest() {
+        let mut buf = [0u8; 16];
+        let full = frame(b"hello");
+
+        for len in [0, 2, 4, full.len() - 1] {
+            let mut tcp = adapter(&full[..len]);
+            assert_eq!(tcp.take_frame(&mut buf).unwrap(), None);
+            assert_eq!(tcp.pending.len(), len);
+        }
+
+        let mut tcp = adapter(&full[..6]);
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), None);
+        tcp.pending.extend_from_slice(&full[6..]);
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert!(tcp.pending.is_empty());
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let mut buf = [0u8; 16];
+        let mut pending = frame(b"first");
+        pending.extend(frame(b""));
+        pending.extend(frame(b"second"));
+        pending.extend(&frame(b"third")[..3]);
+        let mut tcp = adapter(&pending);
+
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), Some(0));
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), Some(6));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), None);
+        assert_eq!(tcp.pending.len(), 3);
+    }
+
+    #[test]
+    fn oversized_length_prefix_drops_the_connection() {
+        let mut buf = [0u8; 16];
+        let mut tcp = adapter(&[0, 0, 1, 0, 1, 2, 3]);
+
+        let err = tcp.take_frame(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(tcp.pending.is_empty());
+        assert!(tcp.stream.is_none());
+
+        let mut tcp = adapter(&frame(&[7; 16]));
+        assert_eq!(tcp.take_frame(&mut buf).unwrap(), Some(16));
+    }
+}
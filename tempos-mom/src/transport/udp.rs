@@ -0,0 +1,98 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use socket2::{Domain, Socket, Type};
+use tempos::endpoint::PeerAddr;
+
+use super::{Capabilities, Role, TransportAdapter, Uri, READ_TIMEOUT};
+
+/// `udp://host:port`, with the optional `priority` (SO_PRIORITY) and `iface`
+/// parameters. Egress sockets are bound to `iface` if set.
+pub struct UdpAdapter {
+    sock: UdpSocket,
+    peer: Option<SocketAddr>,
+}
+
+pub fn open(uri: &Uri, role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+    let addr: SocketAddr = uri
+        .target
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{}: invalid address: {}", uri, e))?;
+    let priority: Option<i32> = uri
+        .param("priority")
+        .map_err(|e| anyhow::anyhow!("{}: {}", uri, e))?;
+    let iface: Option<String> = uri
+        .param("iface")
+        .map_err(|e| anyhow::anyhow!("{}: {}", uri, e))?;
+
+    let adapter = match role {
+        Role::Ingress => {
+            let sock = UdpSocket::bind(addr)?;
+            sock.set_read_timeout(Some(READ_TIMEOUT))?;
+            if let Some(priority) = priority {
+                tempos::set_priority(&sock, priority)?;
+            }
+
+            UdpAdapter { sock, peer: None }
+        }
+        Role::Egress => {
+            // NOTE: the messages are sent without launch time, so the socket
+            //       must not enable SO_TXTIME: an etf qdisc would drop them.
+            let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+            if let Some(iface) = iface {
+                sock.bind_device(Some(iface.as_bytes()))
+                    .map_err(|e| anyhow::anyhow!("{}: unable to bind to {}: {}", uri, iface, e))?;
+            }
+            if let Some(priority) = priority {
+                tempos::set_priority(&sock, priority)?;
+            }
+            let sock: UdpSocket = sock.into();
+
+            UdpAdapter {
+                sock,
+                peer: Some(addr),
+            }
+        }
+    };
+
+    Ok(Box::new(adapter))
+}
+
+impl TransportAdapter for UdpAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (bytes_read, _) = self.sock.recv_from(buf)?;
+
+        Ok(bytes_read)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let peer = self.peer.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "ingress adapter has no peer")
+        })?;
+        self.sock.send_to(buf, peer)?;
+
+        Ok(())
+    }
+
+    fn write_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+        match peer {
+            PeerAddr::Udp(addr) => {
+                self.sock.send_to(buf, addr)?;
+                Ok(())
+            }
+            PeerAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("cannot reach {} over UDP", peer),
+            )),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            datagram: true,
+            priority: true,
+            raw: false,
+            addressed: true,
+        }
+    }
+}
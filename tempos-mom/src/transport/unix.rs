@@ -0,0 +1,75 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+use tempos::endpoint::PeerAddr;
+
+use super::{Capabilities, Role, TransportAdapter, Uri, READ_TIMEOUT};
+
+/// `unix:///path/to/socket`, a Unix datagram socket.
+pub struct UnixAdapter {
+    sock: UnixDatagram,
+    path: PathBuf,
+    role: Role,
+}
+
+pub fn open(uri: &Uri, role: Role) -> anyhow::Result<Box<dyn TransportAdapter>> {
+    let path = PathBuf::from(&uri.target);
+
+    let sock = match role {
+        Role::Ingress => {
+            // NOTE: a socket file left behind by a previous run makes bind fail.
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let sock = UnixDatagram::bind(&path)?;
+            sock.set_read_timeout(Some(READ_TIMEOUT))?;
+            sock
+        }
+        Role::Egress => UnixDatagram::unbound()?,
+    };
+
+    Ok(Box::new(UnixAdapter { sock, path, role }))
+}
+
+impl TransportAdapter for UnixAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.recv(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.sock.send_to(buf, &self.path)?;
+
+        Ok(())
+    }
+
+    fn write_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+        match peer {
+            PeerAddr::Unix(path) => {
+                self.sock.send_to(buf, path)?;
+                Ok(())
+            }
+            PeerAddr::Udp(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("cannot reach {} over a Unix socket", peer),
+            )),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if self.role == Role::Ingress {
+            std::fs::remove_file(&self.path)?;
+        }
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            datagram: true,
+            priority: false,
+            raw: false,
+            addressed: true,
+        }
+    }
+}
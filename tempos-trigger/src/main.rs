@@ -1,12 +1,10 @@
 use clap::Parser;
-use log;
-use std::{
-    io::Write,
-    net::{SocketAddr, UdpSocket},
-    os::fd::IntoRawFd,
-};
 
-use tempos::msg_type;
+use tempos::endpoint::{Endpoint, TemposSocket};
+use tempos::message::{decode, TemposHeader, TemposMessage, MAX_MESSAGE_LEN};
+
+/// How long failures are waited for once every message was sent.
+const FAILURE_LINGER: std::time::Duration = std::time::Duration::from_millis(500);
 
 /// Simple TEMPOS Trigger example
 #[derive(Parser, Debug)]
@@ -16,11 +14,13 @@ struct Args {
     #[clap(short, long)]
     topic: String,
 
-    /// address to send the message to
+    /// local address to bind, ip:port or unix:/path
     #[clap(short, long)]
     addr: String,
 
-    /// address to send the message to
+    /// address to send the message to: ip:port, unix:/path or unix+seqpacket:/path.
+    /// The MOM lanes are datagram only, a seqpacket address reaches a MOM task
+    /// with a unix+seqpacket in_endpoint
     #[clap(short, long)]
     saddr: String,
 
@@ -29,53 +29,123 @@ struct Args {
 
     #[clap(short = 'M', long, default_value = "0")]
     messages: u64,
+
+    /// relative deadline of each message in microseconds, 0 for none
+    #[clap(short, long, default_value = "0")]
+    deadline: u64,
+
+    /// priority class of the messages (0 best-effort, 1 strict)
+    #[clap(short, long, default_value = "0")]
+    priority: u8,
 }
 
-struct NetworkInterface {
-    pub name: String,
-    pub description: String,
-    pub index: u32,
-    // pub mac: Option<MacAddr>,
-    // pub ips: Vec<IpAddr>,
+/// Logs the FAILUREs the MOM sent back for the messages of this trigger,
+/// until the socket has nothing left to read. Returns how many there were.
+fn drain_failures(sock: &TemposSocket, buf: &mut [u8]) -> std::io::Result<u64> {
+    let mut failed = 0;
+    loop {
+        let len = match sock.recv_from(buf) {
+            Ok((len, _)) => len,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                return Ok(failed)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                log::warn!("previous send was refused by the peer");
+                return Ok(failed);
+            }
+            Err(e) => return Err(e),
+        };
+
+        match decode(&buf[..len]) {
+            Ok((
+                _,
+                TemposMessage::Failure {
+                    seq,
+                    chain,
+                    hop,
+                    kind,
+                    status,
+                    topic,
+                    reason,
+                },
+            )) => {
+                failed += 1;
+                log::warn!(
+                    "message {} failed on topic '{}' (chain {}, hop {}, status {}): {}: {}",
+                    seq,
+                    topic,
+                    chain,
+                    hop,
+                    status,
+                    tempos::failure::name(kind),
+                    reason
+                );
+            }
+            Ok(_) => log::debug!("ignoring unexpected message"),
+            Err(e) => log::debug!("ignoring malformed message: {}", e),
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
-    let saddr = args.saddr.parse::<SocketAddr>()?;
+    let saddr: Endpoint = args.saddr.parse()?;
 
-    let sock = UdpSocket::bind(&args.addr)?;
+    // NOTE: seqpacket sockets are connection oriented, there is nothing to bind.
+    let peer = saddr.peer();
+    let sock = match peer {
+        Some(_) => TemposSocket::bind(&args.addr.parse()?)?,
+        None => TemposSocket::connect(&saddr)?,
+    };
 
     let data = std::fs::read("Cargo.toml").unwrap();
-    let data_len = data.len() as u32;
-
-    let topic = args.topic.clone();
-    let topic_len = args.topic.len() as u32;
 
     let mut buf = Vec::with_capacity(2048);
+    let mut recv_buf = vec![0; MAX_MESSAGE_LEN];
+    let mut failed = 0;
 
     let mut start = std::time::Instant::now();
 
     let mut interval_ms = 50;
-    let mut count: i32 = 0;
+    let mut count: u32 = 0;
 
     println!("id,interval,ts_send");
     loop {
-        buf.clear();
-        buf.write(&msg_type::INVOK.to_be_bytes())?;
-        buf.write(&count.to_be_bytes())?;
-        buf.write(&topic_len.to_be_bytes())?;
-        buf.write(&topic.as_bytes())?;
-        buf.write(&data_len.to_be_bytes())?;
-        buf.write(&data).unwrap();
+        let now_ns = tempos::now_ns();
+        let header = TemposHeader {
+            flags: 0,
+            priority: args.priority,
+            timestamp: now_ns,
+            deadline: if args.deadline == 0 {
+                0
+            } else {
+                now_ns + args.deadline * 1000
+            },
+        };
 
-        let now_ns = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+        buf.clear();
+        TemposMessage::Invok {
+            seq: count,
+            chain: 0,
+            hop: 0,
+            topic: &args.topic,
+            data: &data,
+        }
+        .encode(&header, &mut buf);
 
-        sock.send_to(&buf, saddr)?;
+        match &peer {
+            Some(peer) => sock.send_to(&buf, peer)?,
+            None => sock.send(&buf)?,
+        };
+        // NOTE: the sends block, the socket is only polled for failures.
+        sock.set_nonblocking(true)?;
+        failed += drain_failures(&sock, &mut recv_buf)?;
+        sock.set_nonblocking(false)?;
 
         if args.messages == 0 {
             println!("{},{},{}", count, interval_ms, now_ns);
@@ -92,7 +162,7 @@ fn main() -> anyhow::Result<()> {
             std::thread::sleep(std::time::Duration::from_millis(interval_ms));
         } else {
             println!("{},{},{}", count, args.millis, now_ns);
-            if count >= args.messages as i32 {
+            if count as u64 >= args.messages {
                 break;
             }
             std::thread::sleep(std::time::Duration::from_millis(args.millis));
@@ -100,44 +170,11 @@ fn main() -> anyhow::Result<()> {
         count += 1;
     }
 
-    // let iface_name = "lo";
-    // let ifaces = nix::ifaddrs::getifaddrs().unwrap();
-
-    // let network_interfaces: Vec<NetworkInterface> = Vec::new();
-
-    // for iface in ifaces {
-    //     if iface.interface_name != iface_name {
-    //         continue;
-    //     }
-
-    //     println!("Interface: {}", iface.interface_name);
-    //     println!("  Flags: {:?}", iface.flags);
-    //     if let Some(addr) = iface.address {
-    //         if let Some(saddr) = addr.as_sockaddr_in() {
-    //             let ip = std::net::Ipv4Addr::from(saddr.ip());
-    //             println!("  IP: {}", ip)
-    //         }
-    //         if let Some(saddr) = addr.as_sockaddr_in6() {
-    //             let ip = std::net::Ipv6Addr::from(saddr.ip());
-    //             println!("  IP: {}", ip)
-    //         }
-    //     }
-    //     if let Some(mask) = iface.netmask {
-    //         if let Some(saddr) = mask.as_sockaddr_in() {
-    //             let ip = std::net::Ipv4Addr::from(saddr.ip());
-    //             println!("  IP: {}", ip)
-    //         }
-    //         if let Some(saddr) = mask.as_sockaddr_in6() {
-    //             let ip = std::net::Ipv6Addr::from(saddr.ip());
-    //             println!("  IP: {}", ip)
-    //         }
-    //     }
-    //     println!("  Broadcast: {:?}", iface.broadcast);
-    //     println!("  Destination: {:?}", iface.destination);
-    // }
-
-    // let index = nix::net::if_::if_nametoindex(iface_name).unwrap();
-    // println!("Index: {}", index);
+    sock.set_read_timeout(Some(FAILURE_LINGER))?;
+    failed += drain_failures(&sock, &mut recv_buf)?;
+    if failed > 0 {
+        log::warn!("{} of {} messages failed", failed, count + 1);
+    }
 
     log::info!("Done!");
 
@@ -0,0 +1,205 @@
+//! Forwarding benchmark of a MOM lane: one `recv_from`/`send_to` per message
+//! against `recvmmsg`/`sendmmsg` batches, over loopback.
+//!
+//! ```text
+//! sender --> forwarder --> sink
+//! ```
+//!
+//! Every message carries its send time, the sink computes the latency.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use socket2::SockRef;
+use tempos::batch::{RecvBatch, SendBatch};
+use tempos::buffer::{Buffer, BufferPool};
+
+const PAYLOAD_LEN: usize = 64;
+const SOCKET_BUFFER: usize = 8 << 20;
+const SINK_BATCH: usize = 64;
+
+struct Report {
+    received: usize,
+    elapsed: Duration,
+    latencies: Vec<u64>,
+}
+
+fn bind() -> io::Result<UdpSocket> {
+    let sock = UdpSocket::bind("127.0.0.1:0")?;
+    let sock_ref = SockRef::from(&sock);
+    sock_ref.set_recv_buffer_size(SOCKET_BUFFER)?;
+    sock_ref.set_send_buffer_size(SOCKET_BUFFER)?;
+    sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(sock)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Forwards everything received on `sock` to `sink` until nothing arrives
+/// for a second.
+fn forward(sock: UdpSocket, sink: SocketAddr, batch: usize) -> io::Result<()> {
+    let pool = BufferPool::new(batch, PAYLOAD_LEN * 2, 0);
+    let mut bufs: Vec<_> = (0..batch).map(|_| pool.get().unwrap()).collect();
+    let mut recv_batch = RecvBatch::new(batch);
+    let mut send_batch = SendBatch::new(batch);
+    let mut idle = Instant::now();
+
+    while idle.elapsed() < Duration::from_secs(1) {
+        if batch == 1 {
+            let buf = &mut bufs[0];
+            buf.clear();
+            match sock.recv_from(buf.spare_mut()) {
+                Ok((n, _)) => buf.commit(n),
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            }
+            sock.send_to(buf.as_slice(), sink)?;
+        } else {
+            bufs.iter_mut().for_each(|buf| buf.clear());
+            let received = match recv_batch.recv(&sock, &mut bufs) {
+                Ok(received) => received,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            let report =
+                send_batch.send(&sock, bufs[..received].iter().map(|b| (b.as_slice(), sink)));
+            if let Some(e) = report.error {
+                return Err(e);
+            }
+        }
+        idle = Instant::now();
+    }
+
+    Ok(())
+}
+
+fn sink(sock: UdpSocket, n_messages: usize) -> io::Result<Report> {
+    let pool = BufferPool::new(SINK_BATCH, PAYLOAD_LEN * 2, 0);
+    let mut bufs: Vec<_> = (0..SINK_BATCH).map(|_| pool.get().unwrap()).collect();
+    let mut recv_batch = RecvBatch::new(SINK_BATCH);
+    let mut latencies = Vec::with_capacity(n_messages);
+    let mut first = None;
+    let mut last = Instant::now();
+    let mut idle = Instant::now();
+
+    while latencies.len() < n_messages && idle.elapsed() < Duration::from_secs(1) {
+        bufs.iter_mut().for_each(|buf| buf.clear());
+        let received = match recv_batch.recv(&sock, &mut bufs) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let now = tempos::now_ns();
+        last = Instant::now();
+        first.get_or_insert(last);
+        idle = last;
+        for buf in &mut bufs[..received] {
+            let sent = buf.read_u64().unwrap();
+            latencies.push(now.saturating_sub(sent));
+        }
+    }
+
+    Ok(Report {
+        received: latencies.len(),
+        elapsed: first.map_or(Duration::ZERO, |first| last - first),
+        latencies,
+    })
+}
+
+/// Sends `n_messages` to `target`, `rate` messages per second (0 is as fast
+/// as possible).
+fn send(target: SocketAddr, n_messages: usize, rate: u64) -> io::Result<()> {
+    let sock = bind()?;
+    let mut buf = Buffer::with_capacity(PAYLOAD_LEN, 0);
+    let interval = Duration::from_nanos(1_000_000_000u64.checked_div(rate).unwrap_or(0));
+    let start = Instant::now();
+
+    for i in 0..n_messages {
+        let next = start + interval * i as u32;
+        while Instant::now() < next {
+            thread::yield_now();
+        }
+
+        buf.clear();
+        buf.write_u64(tempos::now_ns()).unwrap();
+        buf.write_bytes(&[0; PAYLOAD_LEN - 8]).unwrap();
+        sock.send_to(buf.as_slice(), target)?;
+    }
+
+    Ok(())
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx]
+}
+
+fn run(batch: usize, n_messages: usize, rate: u64) -> io::Result<Report> {
+    let fwd_sock = bind()?;
+    let sink_sock = bind()?;
+    let fwd_addr = fwd_sock.local_addr()?;
+    let sink_addr = sink_sock.local_addr()?;
+
+    let sink = thread::spawn(move || sink(sink_sock, n_messages));
+    let forwarder = thread::spawn(move || forward(fwd_sock, sink_addr, batch));
+
+    send(fwd_addr, n_messages, rate)?;
+
+    let report = sink.join().unwrap()?;
+    forwarder.join().unwrap()?;
+    Ok(report)
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        println!("Usage: {} <batch> <messages> [rate msg/s]", args[0]);
+        return Ok(());
+    }
+
+    let batch = args[1].parse::<usize>().unwrap().max(1);
+    let n_messages = args[2].parse::<usize>().unwrap();
+    let rate = args.get(3).map_or(0, |rate| rate.parse::<u64>().unwrap());
+
+    println!("mode,batch,sent,received,msg_per_s,p50_us,p99_us,p999_us,max_us");
+    let modes = if batch == 1 { vec![1] } else { vec![1, batch] };
+    for batch in modes {
+        let mut report = run(batch, n_messages, rate)?;
+        report.latencies.sort_unstable();
+
+        let secs = report.elapsed.as_secs_f64();
+        let throughput = if secs > 0.0 {
+            report.received as f64 / secs
+        } else {
+            0.0
+        };
+        let us = |ns: u64| ns as f64 / 1000.0;
+        println!(
+            "{},{},{},{},{:.0},{:.1},{:.1},{:.1},{:.1}",
+            if batch == 1 { "single" } else { "batched" },
+            batch,
+            n_messages,
+            report.received,
+            throughput,
+            us(percentile(&report.latencies, 0.5)),
+            us(percentile(&report.latencies, 0.99)),
+            us(percentile(&report.latencies, 0.999)),
+            us(report.latencies.last().copied().unwrap_or(0)),
+        );
+    }
+
+    Ok(())
+}
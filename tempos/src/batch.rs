@@ -0,0 +1,247 @@
+//! Batched datagram I/O with `recvmmsg`/`sendmmsg`: one syscall moves up
+//! to a whole batch of messages.
+//!
+//! The message headers are allocated once, so a batch does not allocate.
+//! They point into each other, hence the batches are neither `Send` nor
+//! `Sync` and must be created by the thread using them.
+
+use socket2::SockAddr;
+use std::{
+    io,
+    mem::{self, MaybeUninit},
+    net::SocketAddr,
+    ops::DerefMut,
+    os::unix::prelude::*,
+    ptr,
+};
+
+use crate::buffer::Buffer;
+
+/// `msghdr`s and the address storage they point to.
+struct Headers {
+    hdrs: Vec<libc::mmsghdr>,
+    iovs: Vec<libc::iovec>,
+    addrs: Vec<libc::sockaddr_storage>,
+}
+
+impl Headers {
+    fn new(n: usize) -> Self {
+        let n = n.max(1);
+        let mut headers = Self {
+            // SAFETY: all-zero is a valid value for these C structs.
+            hdrs: vec![unsafe { MaybeUninit::zeroed().assume_init() }; n],
+            iovs: vec![
+                libc::iovec {
+                    iov_base: ptr::null_mut(),
+                    iov_len: 0,
+                };
+                n
+            ],
+            addrs: vec![unsafe { MaybeUninit::zeroed().assume_init() }; n],
+        };
+
+        // NOTE: the vectors are never resized, so these pointers stay valid.
+        for i in 0..n {
+            let hdr = &mut headers.hdrs[i].msg_hdr;
+            hdr.msg_iov = &mut headers.iovs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_name = &mut headers.addrs[i] as *mut _ as *mut libc::c_void;
+        }
+
+        headers
+    }
+
+    fn capacity(&self) -> usize {
+        self.hdrs.len()
+    }
+}
+
+pub struct RecvBatch {
+    headers: Headers,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            headers: Headers::new(size),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.headers.capacity()
+    }
+
+    /// Receives up to `bufs.len()` datagrams, appending one to each buffer.
+    /// Blocks (up to the socket read timeout) until the first datagram
+    /// arrives, then takes whatever else is already queued.
+    pub fn recv<S, B>(&mut self, sock: &S, bufs: &mut [B]) -> io::Result<usize>
+    where
+        S: AsRawFd,
+        B: DerefMut<Target = Buffer>,
+    {
+        let n = bufs.len().min(self.capacity());
+        for (i, buf) in bufs[..n].iter_mut().enumerate() {
+            let spare = buf.spare_mut();
+            self.headers.iovs[i] = libc::iovec {
+                iov_base: spare.as_mut_ptr() as *mut libc::c_void,
+                iov_len: spare.len(),
+            };
+            let hdr = &mut self.headers.hdrs[i];
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_hdr.msg_flags = 0;
+            hdr.msg_len = 0;
+        }
+
+        let received = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                self.headers.hdrs.as_mut_ptr(),
+                n as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let received = received as usize;
+        for (i, buf) in bufs[..received].iter_mut().enumerate() {
+            buf.commit(self.headers.hdrs[i].msg_len as usize);
+        }
+
+        Ok(received)
+    }
+
+    /// Sender of the `i`-th datagram of the last [`RecvBatch::recv`].
+    pub fn addr(&self, i: usize) -> Option<SocketAddr> {
+        let hdr = &self.headers.hdrs.get(i)?.msg_hdr;
+        let addr = unsafe { SockAddr::new(self.headers.addrs[i], hdr.msg_namelen) };
+        addr.as_socket()
+    }
+}
+
+/// Outcome of a [`SendBatch::send`].
+#[derive(Debug, Default)]
+pub struct SendReport {
+    pub sent: usize,
+    /// Datagrams the kernel refused, the others were still sent.
+    pub dropped: usize,
+    /// Error of the last dropped datagram.
+    pub error: Option<io::Error>,
+}
+
+pub struct SendBatch {
+    headers: Headers,
+}
+
+impl SendBatch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            headers: Headers::new(size),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.headers.capacity()
+    }
+
+    /// Sends the `messages` with as few `sendmmsg` calls as possible. A
+    /// datagram the kernel refuses, e.g. for an unreachable peer, is dropped
+    /// and the rest of the batch is still sent.
+    pub fn send<'a, S, I>(&mut self, sock: &S, messages: I) -> SendReport
+    where
+        S: AsRawFd,
+        I: IntoIterator<Item = (&'a [u8], SocketAddr)>,
+    {
+        let mut messages = messages.into_iter().peekable();
+        let mut report = SendReport::default();
+
+        while messages.peek().is_some() {
+            let mut n = 0;
+            for (buf, addr) in messages.by_ref().take(self.capacity()) {
+                let addr = SockAddr::from(addr);
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        addr.as_ptr() as *const u8,
+                        &mut self.headers.addrs[n] as *mut _ as *mut u8,
+                        addr.len() as usize,
+                    );
+                }
+                self.headers.iovs[n] = libc::iovec {
+                    iov_base: buf.as_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                };
+                self.headers.hdrs[n].msg_hdr.msg_namelen = addr.len();
+                n += 1;
+            }
+
+            // NOTE: sendmmsg stops at the first datagram it fails to send and
+            //       only reports the error when that one comes first, so
+            //       the batch is resumed from there.
+            let mut start = 0;
+            while start < n {
+                // NOTE: the iovecs point into `messages`, which outlives the call.
+                let ret = unsafe {
+                    libc::sendmmsg(
+                        sock.as_raw_fd(),
+                        self.headers.hdrs.as_mut_ptr().add(start),
+                        (n - start) as libc::c_uint,
+                        0,
+                    )
+                };
+                if ret < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    report.dropped += 1;
+                    report.error = Some(err);
+                    start += 1;
+                } else {
+                    report.sent += ret as usize;
+                    start += ret as usize;
+                }
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn send_skips_a_refused_datagram() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let to = sink.local_addr().unwrap();
+        // NOTE: an IPv4 socket can't send to an IPv6 address.
+        let refused: SocketAddr = "[::1]:9".parse().unwrap();
+
+        let mut batch = SendBatch::new(4);
+        let messages = [
+            (&b"a"[..], to),
+            (&b"b"[..], refused),
+            (&b"c"[..], to),
+            (&b"d"[..], refused),
+            (&b"e"[..], to),
+        ];
+        let report = batch.send(&sock, messages.iter().copied());
+
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.dropped, 2);
+        assert!(report.error.is_some());
+
+        let mut buf = [0; 8];
+        for expected in [b"a", b"c", b"e"] {
+            let (len, _) = sink.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+}
@@ -1,63 +1,513 @@
-use core::slice::*;
+//! Fixed-capacity packet buffers and a pool to recycle them.
+//!
+//! ```text
+//! 0        start    current        end         capacity
+//! |headroom |  read  |    unread    |  tailroom  |
+//! ```
+//!
+//! Data is appended at `end` and read from `current`. The headroom reserved
+//! in front of `start` lets a header be prepended without moving the data.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferError {
+    /// Reading `needed` bytes with only `available` left.
+    Underflow { needed: usize, available: usize },
+    /// Writing `needed` bytes with only `available` of room.
+    Overflow { needed: usize, available: usize },
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferError::Underflow { needed, available } => write!(
+                f,
+                "cannot read {} bytes, {} left in the buffer",
+                needed, available
+            ),
+            BufferError::Overflow { needed, available } => write!(
+                f,
+                "cannot write {} bytes, {} of room in the buffer",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {}
 
-#[derive(Default)]
 pub struct Buffer {
-    pub buf: Vec<u8>,
+    buf: Box<[u8]>,
+    /// Headroom restored by `clear`.
+    reserved: usize,
     start: usize,
     end: usize,
     current: usize,
 }
 
+macro_rules! read_be {
+    ($name:ident, $ty:ty) => {
+        pub fn $name(&mut self) -> Result<$ty, BufferError> {
+            let bytes = self.read_bytes(std::mem::size_of::<$ty>())?;
+            Ok(<$ty>::from_be_bytes(bytes.try_into().unwrap()))
+        }
+    };
+}
+
+macro_rules! write_be {
+    ($name:ident, $ty:ty) => {
+        pub fn $name(&mut self, value: $ty) -> Result<(), BufferError> {
+            self.write_bytes(&value.to_be_bytes())
+        }
+    };
+}
+
 impl Buffer {
-    pub fn copy_bytes(&self, into: &mut [u8], start: usize) -> usize {
-        let len = into.len();
-        into[0..len].copy_from_slice(&self.buf[start..start + len]);
-        1
+    /// An empty buffer of `capacity` bytes, `headroom` of which are kept in
+    /// front of the data for [`Buffer::prepend`].
+    pub fn with_capacity(capacity: usize, headroom: usize) -> Self {
+        let headroom = headroom.min(capacity);
+        Self {
+            buf: vec![0; capacity].into_boxed_slice(),
+            reserved: headroom,
+            start: headroom,
+            end: headroom,
+            current: headroom,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
     }
 
-    // pub fn read(&mut self, into: &mut [u8], n: usize) -> usize {
-    //     self.copy_bytes(into, self.current, n);
-    // }
+    /// Room left in front of the data.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
 
-    // pub fn read_byte(&mut self) -> Option<u8> {
-    //     let mut byte = 0;
-    //     if self.read(core::slice::from_mut(&mut byte), 1) != 0 {
-    //         Some(byte)
-    //     } else {
-    //         Nonex
-    //     }
-    // }
+    /// Room left after the data.
+    pub fn tailroom(&self) -> usize {
+        self.buf.len() - self.end
+    }
+
+    /// Bytes not read yet.
+    pub fn remaining(&self) -> usize {
+        self.end - self.current
+    }
+
+    /// Empties the buffer and restores its headroom.
+    pub fn clear(&mut self) {
+        self.start = self.reserved;
+        self.end = self.reserved;
+        self.current = self.reserved;
+    }
+
+    /// The whole data, regardless of the read cursor.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.end]
+    }
+
+    /// The data after the read cursor.
+    pub fn unread(&self) -> &[u8] {
+        &self.buf[self.current..self.end]
+    }
+
+    /// The tailroom, to receive into. Received bytes must then be added to
+    /// the data with [`Buffer::commit`].
+    pub fn spare_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.end..]
+    }
+
+    /// Adds `n` bytes written through [`Buffer::spare_mut`] to the data.
+    pub fn commit(&mut self, n: usize) {
+        assert!(n <= self.tailroom(), "commit past the end of the buffer");
+        self.end += n;
+    }
+
+    /// Replaces the data with `data`.
+    pub fn set_data(&mut self, data: &[u8]) -> Result<(), BufferError> {
+        self.clear();
+        self.write_bytes(data)
+    }
+
+    /// Moves the read cursor back to the beginning of the data.
+    pub fn rewind(&mut self) {
+        self.current = self.start;
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&[u8], BufferError> {
+        if n > self.remaining() {
+            return Err(BufferError::Underflow {
+                needed: n,
+                available: self.remaining(),
+            });
+        }
+
+        let bytes = &self.buf[self.current..self.current + n];
+        self.current += n;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, n: usize) -> Result<(), BufferError> {
+        self.read_bytes(n).map(|_| ())
+    }
+
+    read_be!(read_u8, u8);
+    read_be!(read_u16, u16);
+    read_be!(read_u32, u32);
+    read_be!(read_u64, u64);
+
+    /// Appends `bytes` to the data.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), BufferError> {
+        if bytes.len() > self.tailroom() {
+            return Err(BufferError::Overflow {
+                needed: bytes.len(),
+                available: self.tailroom(),
+            });
+        }
+
+        self.buf[self.end..self.end + bytes.len()].copy_from_slice(bytes);
+        self.end += bytes.len();
+        Ok(())
+    }
+
+    write_be!(write_u8, u8);
+    write_be!(write_u16, u16);
+    write_be!(write_u32, u32);
+    write_be!(write_u64, u64);
+
+    /// Inserts `bytes` in front of the data, using the headroom.
+    pub fn prepend(&mut self, bytes: &[u8]) -> Result<(), BufferError> {
+        if bytes.len() > self.headroom() {
+            return Err(BufferError::Overflow {
+                needed: bytes.len(),
+                available: self.headroom(),
+            });
+        }
+
+        // NOTE: a cursor at the old start keeps pointing at the old data.
+        let at_start = self.current == self.start;
+        self.start -= bytes.len();
+        self.buf[self.start..self.start + bytes.len()].copy_from_slice(bytes);
+        if at_start {
+            self.current = self.start;
+        }
+        Ok(())
+    }
 }
 
 impl From<Vec<u8>> for Buffer {
     fn from(buf: Vec<u8>) -> Self {
         let end = buf.len();
         Self {
-            buf: buf,
+            buf: buf.into_boxed_slice(),
+            reserved: 0,
+            start: 0,
             end,
-            ..Default::default()
+            current: 0,
         }
     }
 }
 
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buffer")
+            .field("capacity", &self.capacity())
+            .field("start", &self.start)
+            .field("current", &self.current)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// A fixed set of preallocated buffers shared between threads.
 pub struct BufferPool {
-    buffers: Vec<Buffer>,
+    free: Mutex<Vec<Buffer>>,
+    count: usize,
 }
 
 impl BufferPool {
-    pub fn new() -> Self {
-        Self { buffers: vec![] }
+    /// Allocates `count` buffers of `capacity` bytes with `headroom` bytes of
+    /// headroom each. No allocation happens after this.
+    pub fn new(count: usize, capacity: usize, headroom: usize) -> Self {
+        let free = (0..count)
+            .map(|_| Buffer::with_capacity(capacity, headroom))
+            .collect();
+
+        Self {
+            free: Mutex::new(free),
+            count,
+        }
+    }
+
+    /// Takes an empty buffer out of the pool, `None` if all of them are in
+    /// use. The buffer goes back to the pool when dropped.
+    pub fn get(&self) -> Option<PooledBuffer<'_>> {
+        let mut buf = self.free.lock().unwrap().pop()?;
+        buf.clear();
+
+        Some(PooledBuffer {
+            buf: Some(buf),
+            pool: self,
+        })
+    }
+
+    /// Buffers currently in the pool.
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+pub struct PooledBuffer<'a> {
+    buf: Option<Buffer>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Buffer {
+        // NOTE: only None while being dropped.
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Buffer {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.free.lock().unwrap().push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_and_read() {
+        let mut buf = Buffer::with_capacity(16, 4);
+        assert!(buf.is_empty());
+        assert_eq!(buf.headroom(), 4);
+        assert_eq!(buf.tailroom(), 12);
+        assert_eq!(buf.spare_mut().len(), 12);
+
+        buf.spare_mut()[..6].copy_from_slice(&[1, 0, 2, 0, 0, 0]);
+        buf.commit(6);
+        assert_eq!(buf.len(), 6);
+        assert_eq!(buf.tailroom(), 6);
+        assert_eq!(buf.as_slice(), [1, 0, 2, 0, 0, 0]);
+
+        assert_eq!(buf.read_u8(), Ok(1));
+        assert_eq!(buf.read_u16(), Ok(2));
+        assert_eq!(buf.remaining(), 3);
+        assert_eq!(buf.unread(), [0, 0, 0]);
+        assert_eq!(buf.as_slice().len(), 6);
+
+        buf.skip(3).unwrap();
+        assert_eq!(buf.remaining(), 0);
+        buf.rewind();
+        assert_eq!(buf.remaining(), 6);
     }
 
-    pub fn get(&mut self) -> Buffer {
-        if self.buffers.is_empty() {
-            Buffer::default()
-        } else {
-            self.buffers.pop().unwrap()
+    #[test]
+    #[should_panic(expected = "commit past the end of the buffer")]
+    fn commit_past_the_end() {
+        let mut buf = Buffer::with_capacity(8, 2);
+        buf.commit(7);
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let mut buf = Buffer::with_capacity(32, 0);
+        buf.write_u8(0xab).unwrap();
+        buf.write_u16(0x1234).unwrap();
+        buf.write_u32(0xdead_beef).unwrap();
+        buf.write_u64(u64::MAX - 1).unwrap();
+        buf.write_bytes(b"xy").unwrap();
+        assert_eq!(&buf.as_slice()[..3], [0xab, 0x12, 0x34]);
+        assert_eq!(buf.len(), 17);
+
+        assert_eq!(buf.read_u8(), Ok(0xab));
+        assert_eq!(buf.read_u16(), Ok(0x1234));
+        assert_eq!(buf.read_u32(), Ok(0xdead_beef));
+        assert_eq!(buf.read_u64(), Ok(u64::MAX - 1));
+        assert_eq!(buf.read_bytes(2), Ok(&b"xy"[..]));
+    }
+
+    #[test]
+    fn underflow() {
+        let mut buf = Buffer::from(vec![1, 2, 3]);
+        assert_eq!(
+            buf.read_u32(),
+            Err(BufferError::Underflow {
+                needed: 4,
+                available: 3
+            })
+        );
+        // NOTE: a failed read leaves the cursor in place.
+        assert_eq!(buf.read_u16(), Ok(0x0102));
+        assert_eq!(
+            buf.skip(2),
+            Err(BufferError::Underflow {
+                needed: 2,
+                available: 1
+            })
+        );
+        assert_eq!(buf.read_u8(), Ok(3));
+        assert!(buf.read_u8().is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut buf = Buffer::with_capacity(8, 2);
+        buf.write_u32(1).unwrap();
+        assert_eq!(
+            buf.write_u32(2),
+            Err(BufferError::Overflow {
+                needed: 4,
+                available: 2
+            })
+        );
+        // NOTE: a failed write leaves the data unchanged.
+        assert_eq!(buf.as_slice(), [0, 0, 0, 1]);
+        buf.write_u16(2).unwrap();
+        assert_eq!(buf.tailroom(), 0);
+
+        assert_eq!(
+            buf.set_data(&[0; 7]),
+            Err(BufferError::Overflow {
+                needed: 7,
+                available: 6
+            })
+        );
+        buf.set_data(&[9; 6]).unwrap();
+        assert_eq!(buf.as_slice(), [9; 6]);
+    }
+
+    #[test]
+    fn prepend_uses_the_headroom() {
+        let mut buf = Buffer::with_capacity(16, 4);
+        buf.write_bytes(b"data").unwrap();
+
+        buf.prepend(&[1, 2]).unwrap();
+        assert_eq!(buf.headroom(), 2);
+        assert_eq!(buf.as_slice(), b"\x01\x02data");
+        assert_eq!(buf.unread(), b"\x01\x02data");
+
+        // NOTE: a cursor past the start keeps pointing at the same byte.
+        assert_eq!(buf.read_u8(), Ok(1));
+        buf.prepend(&[0]).unwrap();
+        assert_eq!(buf.as_slice(), b"\x00\x01\x02data");
+        assert_eq!(buf.unread(), b"\x02data");
+
+        assert_eq!(
+            buf.prepend(&[0; 2]),
+            Err(BufferError::Overflow {
+                needed: 2,
+                available: 1
+            })
+        );
+        assert_eq!(buf.len(), 7);
+
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(buf.headroom(), 4);
+        assert_eq!(buf.tailroom(), 12);
+    }
+
+    #[test]
+    fn headroom_is_capped_by_the_capacity() {
+        let mut buf = Buffer::with_capacity(4, 8);
+        assert_eq!(buf.headroom(), 4);
+        assert_eq!(buf.tailroom(), 0);
+        assert!(buf.write_u8(0).is_err());
+        buf.prepend(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(buf.as_slice(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pooled_buffers_go_back_to_the_pool() {
+        let pool = BufferPool::new(2, 64, 8);
+        assert_eq!(pool.count(), 2);
+        assert_eq!(pool.available(), 2);
+
+        let mut a = pool.get().unwrap();
+        a.write_bytes(b"stale").unwrap();
+        a.prepend(&[0; 8]).unwrap();
+        assert_eq!(pool.available(), 1);
+        {
+            let b = pool.get().unwrap();
+            assert_eq!(b.capacity(), 64);
+            assert_eq!(b.headroom(), 8);
+            assert_eq!(pool.available(), 0);
+
+            // NOTE: exhausted, nothing is allocated.
+            assert!(pool.get().is_none());
         }
+        assert_eq!(pool.available(), 1);
+
+        drop(a);
+        assert_eq!(pool.available(), 2);
+
+        // NOTE: buffers come back empty, with their headroom restored.
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        for buf in [&a, &b] {
+            assert!(buf.is_empty());
+            assert_eq!(buf.headroom(), 8);
+        }
+        assert!(pool.get().is_none());
+        drop((a, b));
+        assert_eq!(pool.available(), pool.count());
     }
 
-    pub fn put(&mut self, buf: Buffer) {
-        self.buffers.push(buf);
+    #[test]
+    fn pooled_buffers_are_shared_between_threads() {
+        let pool = std::sync::Arc::new(BufferPool::new(4, 64, 0));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        if let Some(mut buf) = pool.get() {
+                            buf.write_u64(42).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(pool.available(), 4);
     }
 }
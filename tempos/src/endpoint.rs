@@ -0,0 +1,349 @@
+//! Addresses and sockets shared by the trigger, the MOM and the invokers.
+//!
+//! Besides UDP, co-located components can talk over Unix sockets:
+//!
+//! ```text
+//! 127.0.0.1:3330                  UDP (also udp://127.0.0.1:3330)
+//! unix:/run/tempos/mom.sock       Unix datagram
+//! unix+seqpacket:/run/tempos/t0   Unix SOCK_SEQPACKET, connection oriented
+//! ```
+//!
+//! Endpoints are the [`Uri`]s of these schemes, without parameters.
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    os::unix::{
+        net::{UnixDatagram, UnixListener, UnixStream},
+        prelude::*,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::uri::Uri;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+    Seqpacket(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointError(String);
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid endpoint '{}', expected ip:port, udp://ip:port, unix:/path or unix+seqpacket:/path",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for EndpointError {}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || EndpointError(s.to_string());
+        let uri: Uri = s.parse().map_err(|_| err())?;
+        if !uri.params.is_empty() {
+            return Err(err());
+        }
+
+        match uri.scheme.as_str() {
+            "udp" => uri.target.parse().map(Endpoint::Udp).map_err(|_| err()),
+            "unix" => Ok(Endpoint::Unix(PathBuf::from(uri.target))),
+            "unix+seqpacket" => Ok(Endpoint::Seqpacket(PathBuf::from(uri.target))),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Udp(addr) => write!(f, "udp://{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Seqpacket(path) => write!(f, "unix+seqpacket:{}", path.display()),
+        }
+    }
+}
+
+impl Endpoint {
+    /// The address to use with [`TemposSocket::send_to`], `None` for
+    /// connection oriented endpoints.
+    pub fn peer(&self) -> Option<PeerAddr> {
+        match self {
+            Endpoint::Udp(addr) => Some(PeerAddr::Udp(*addr)),
+            Endpoint::Unix(path) => Some(PeerAddr::Unix(path.clone())),
+            Endpoint::Seqpacket(_) => None,
+        }
+    }
+}
+
+/// Source or destination of a datagram.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Udp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Udp(addr)
+    }
+}
+
+pub enum TemposSocket {
+    Udp(UdpSocket),
+    Unix {
+        sock: UnixDatagram,
+        /// Bound path, removed when the socket is dropped.
+        path: Option<PathBuf>,
+    },
+    Seqpacket(UnixStream),
+}
+
+impl TemposSocket {
+    /// Binds a socket receiving on `endpoint`. A Unix socket file left behind
+    /// by a previous run is replaced.
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Udp(addr) => Ok(TemposSocket::Udp(UdpSocket::bind(addr)?)),
+            Endpoint::Unix(path) => {
+                prepare_path(path)?;
+                Ok(TemposSocket::Unix {
+                    sock: UnixDatagram::bind(path)?,
+                    path: Some(path.clone()),
+                })
+            }
+            Endpoint::Seqpacket(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is connection oriented, use seqpacket_listen", endpoint),
+            )),
+        }
+    }
+
+    /// An unbound socket able to send to `peer`, for messages that need no
+    /// reply.
+    pub fn unbound_for(peer: &PeerAddr) -> io::Result<Self> {
+        match peer {
+            PeerAddr::Udp(addr) => {
+                let any = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                Ok(TemposSocket::Udp(UdpSocket::bind(any)?))
+            }
+            PeerAddr::Unix(_) => Ok(TemposSocket::Unix {
+                sock: UnixDatagram::unbound()?,
+                path: None,
+            }),
+        }
+    }
+
+    /// Connects to `endpoint`, later messages are sent with [`TemposSocket::send`].
+    pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Seqpacket(path) => Ok(TemposSocket::Seqpacket(seqpacket_connect(path)?)),
+            _ => {
+                // NOTE: peer() is only None for seqpacket endpoints.
+                let peer = endpoint.peer().unwrap();
+                let sock = Self::unbound_for(&peer)?;
+                match (&sock, &peer) {
+                    (TemposSocket::Udp(s), PeerAddr::Udp(addr)) => s.connect(addr)?,
+                    (TemposSocket::Unix { sock: s, .. }, PeerAddr::Unix(path)) => {
+                        s.connect(path)?
+                    }
+                    _ => unreachable!(),
+                }
+                Ok(sock)
+            }
+        }
+    }
+
+    /// Receives one message. The sender is `None` for unbound Unix sockets
+    /// and connected seqpacket sockets.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PeerAddr>)> {
+        match self {
+            TemposSocket::Udp(sock) => {
+                let (n, addr) = sock.recv_from(buf)?;
+                Ok((n, Some(PeerAddr::Udp(addr))))
+            }
+            TemposSocket::Unix { sock, .. } => {
+                let (n, addr) = sock.recv_from(buf)?;
+                Ok((n, addr.as_pathname().map(|p| PeerAddr::Unix(p.to_owned()))))
+            }
+            TemposSocket::Seqpacket(stream) => {
+                let n = (&*stream).read(buf)?;
+                if n == 0 {
+                    return Err(io::ErrorKind::ConnectionReset.into());
+                }
+                Ok((n, None))
+            }
+        }
+    }
+
+    pub fn send_to(&self, buf: &[u8], peer: &PeerAddr) -> io::Result<usize> {
+        match (self, peer) {
+            (TemposSocket::Udp(sock), PeerAddr::Udp(addr)) => sock.send_to(buf, addr),
+            (TemposSocket::Unix { sock, .. }, PeerAddr::Unix(path)) => sock.send_to(buf, path),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unable to reach {} from this socket", peer),
+            )),
+        }
+    }
+
+    /// Sends on a socket returned by [`TemposSocket::connect`].
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TemposSocket::Udp(sock) => sock.send(buf),
+            TemposSocket::Unix { sock, .. } => sock.send(buf),
+            TemposSocket::Seqpacket(stream) => (&*stream).write(buf),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            TemposSocket::Udp(sock) => sock.set_read_timeout(timeout),
+            TemposSocket::Unix { sock, .. } => sock.set_read_timeout(timeout),
+            TemposSocket::Seqpacket(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            TemposSocket::Udp(sock) => sock.set_nonblocking(nonblocking),
+            TemposSocket::Unix { sock, .. } => sock.set_nonblocking(nonblocking),
+            TemposSocket::Seqpacket(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Whether `SO_PRIORITY` has an effect, i.e. the traffic leaves the host.
+    pub fn is_inet(&self) -> bool {
+        matches!(self, TemposSocket::Udp(_))
+    }
+}
+
+impl AsRawFd for TemposSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            TemposSocket::Udp(sock) => sock.as_raw_fd(),
+            TemposSocket::Unix { sock, .. } => sock.as_raw_fd(),
+            TemposSocket::Seqpacket(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for TemposSocket {
+    fn drop(&mut self) {
+        if let TemposSocket::Unix {
+            path: Some(path), ..
+        } = self
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Removes a stale socket file at `path` and creates its parent directory.
+fn prepare_path(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Listens for `SOCK_SEQPACKET` connections at `path`. Every read on an
+/// accepted stream returns exactly one message.
+pub fn seqpacket_listen(path: &Path) -> io::Result<UnixListener> {
+    prepare_path(path)?;
+
+    let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(128)?;
+
+    Ok(UnixListener::from(OwnedFd::from(socket)))
+}
+
+pub fn seqpacket_connect(path: &Path) -> io::Result<UnixStream> {
+    let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+    socket.connect(&SockAddr::unix(path)?)?;
+
+    Ok(UnixStream::from(OwnedFd::from(socket)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoints() {
+        let udp = Endpoint::Udp(([127, 0, 0, 1], 3330).into());
+        for s in [
+            "127.0.0.1:3330",
+            "udp://127.0.0.1:3330",
+            "udp:127.0.0.1:3330",
+            "udp/127.0.0.1:3330",
+        ] {
+            assert_eq!(s.parse(), Ok(udp.clone()), "{}", s);
+        }
+
+        let unix = Endpoint::Unix(PathBuf::from("/run/tempos/mom.sock"));
+        for s in ["unix:/run/tempos/mom.sock", "unix:///run/tempos/mom.sock"] {
+            assert_eq!(s.parse(), Ok(unix.clone()), "{}", s);
+        }
+
+        assert_eq!(
+            "unix+seqpacket:/run/tempos/t0".parse(),
+            Ok(Endpoint::Seqpacket(PathBuf::from("/run/tempos/t0")))
+        );
+    }
+
+    #[test]
+    fn endpoints_are_uris() {
+        for s in [
+            "127.0.0.1:3330",
+            "udp://127.0.0.1:3330",
+            "udp://127.0.0.1:99999",
+            "udp://127.0.0.1:3330?priority=3",
+            "unix:",
+            "unix:/run/tempos/mom.sock",
+            "unix+seqpacket:/run/tempos/t0",
+            "tcp://10.0.0.2:4000",
+            "/run/tempos/mom.sock",
+            "",
+        ] {
+            let endpoint = s.parse::<Endpoint>();
+            match s.parse::<Uri>() {
+                Ok(uri) if uri.params.is_empty() && endpoint.is_ok() => {
+                    assert_eq!(endpoint.unwrap().to_string().parse::<Uri>(), Ok(uri));
+                }
+                _ => assert_eq!(endpoint, Err(EndpointError(s.to_string())), "{}", s),
+            }
+        }
+    }
+}
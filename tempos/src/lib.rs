@@ -1,19 +1,67 @@
-use nix::{
-    libc::msghdr,
-    sys::socket::{sendmsg, setsockopt, sockopt, MsgFlags, SockAddr},
-};
+use nix::sys::socket::{sendmsg, setsockopt, sockopt, MsgFlags};
+use nix::time::ClockId;
 use socket2::{Domain, Socket, Type};
-use std::{os::unix::prelude::*, str::FromStr};
+use std::os::unix::prelude::*;
 
+pub mod batch;
 pub mod buffer;
+pub mod endpoint;
 pub mod message;
 pub mod node;
+pub mod shm;
+pub mod txtime;
+pub mod uri;
+#[cfg(feature = "uring")]
+pub mod uring;
 
 pub mod msg_type {
     pub const REGISTRATION: u8 = 0x00;
     pub const INVOK: u8 = 0x01;
     pub const MONITORING: u8 = 0x02;
     pub const UNREGISTRATION: u8 = 0x03;
+    pub const UNSUBSCRIBE: u8 = 0x04;
+    pub const FAILURE: u8 = 0x05;
+}
+
+/// Why an invocation failed, carried by FAILURE messages.
+pub mod failure {
+    /// The function returned the negative status carried along.
+    pub const STATUS: u8 = 0x00;
+    /// The function trapped.
+    pub const TRAP: u8 = 0x01;
+    /// The module does not export the function.
+    pub const NO_FUNCTION: u8 = 0x02;
+    /// The module could not be loaded or instantiated.
+    pub const MODULE: u8 = 0x03;
+    /// The input or output does not fit the memory of the instance or the
+    /// message carrying it.
+    pub const IO: u8 = 0x04;
+    /// The invoker serves no function for the topic.
+    pub const UNKNOWN_TOPIC: u8 = 0x05;
+
+    pub fn name(kind: u8) -> &'static str {
+        match kind {
+            STATUS => "status",
+            TRAP => "trap",
+            NO_FUNCTION => "no function",
+            MODULE => "module",
+            IO => "io",
+            UNKNOWN_TOPIC => "unknown topic",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Bits of `TemposHeader::flags`.
+pub mod flags {
+    /// On REGISTRATION: the node also reads INVOKs from the shared-memory
+    /// ring at `shm::ring_path(node_id)`.
+    pub const SHM_RING: u8 = 0x01;
+}
+
+pub mod priority_class {
+    pub const BEST_EFFORT: u8 = 0x00;
+    pub const STRICT: u8 = 0x01;
 }
 
 #[inline(always)]
@@ -21,48 +69,87 @@ pub fn message_id(header: u8) -> u8 {
     header
 }
 
+/// `linux/net_tstamp.h`, named as in the kernel.
+#[allow(non_camel_case_types)]
 pub type txtime_flags = ::std::os::raw::c_uint;
 pub const SOF_TXTIME_DEADLINE_MODE: txtime_flags = 1;
 pub const SOF_TXTIME_REPORT_ERRORS: txtime_flags = 2;
 pub const SOF_TXTIME_FLAGS_LAST: txtime_flags = 2;
 pub const SOF_TXTIME_FLAGS_MASK: txtime_flags = 3;
 
-pub fn open_socket(prio: i32, iface: &str) -> Socket {
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+/// Sets `SO_PRIORITY` on `sock`, which selects its taprio traffic class.
+pub fn set_priority<S: AsRawFd>(sock: &S, prio: i32) -> nix::Result<()> {
+    setsockopt(sock.as_raw_fd(), sockopt::Priority, &prio)
+}
 
-    let sockfd = socket.as_raw_fd();
-    setsockopt(sockfd, sockopt::Priority, &prio).unwrap();
+/// Clock of the launch times unless configured otherwise, the one etf and
+/// taprio schedule on.
+pub const DEFAULT_TXTIME_CLOCK: ClockId = ClockId::CLOCK_TAI;
 
-    socket.bind_device(Some(iface.as_bytes())).unwrap();
+/// Opens a UDP socket bound to `iface` with priority `prio` whose messages
+/// carry a launch time in `clock` (see [`send_message`]). Launch-time
+/// errors are queued on the socket error queue, see
+/// [`txtime::read_errors`].
+pub fn open_socket(prio: i32, iface: &str, clock: ClockId) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)
+        .map_err(|e| context(e, "unable to create the SO_TXTIME socket"))?;
 
-    let mut sk_txtime = nix::libc::sock_txtime {
-        clockid: nix::libc::SOF_TIMESTAMPING_TX_HARDWARE as i32,
-        flags: 0,
-    };
+    let sockfd = socket.as_raw_fd();
+    setsockopt(sockfd, sockopt::Priority, &prio)
+        .map_err(|e| context(e.into(), format!("unable to set SO_PRIORITY {}", prio)))?;
 
-    sk_txtime.flags = SOF_TXTIME_DEADLINE_MODE | SOF_TXTIME_REPORT_ERRORS;
+    socket
+        .bind_device(Some(iface.as_bytes()))
+        .map_err(|e| context(e, format!("unable to bind to {}", iface)))?;
 
-    setsockopt(sockfd, sockopt::TxTime, &sk_txtime).unwrap();
+    let sk_txtime = nix::libc::sock_txtime {
+        clockid: clock.as_raw(),
+        flags: SOF_TXTIME_DEADLINE_MODE | SOF_TXTIME_REPORT_ERRORS,
+    };
+    setsockopt(sockfd, sockopt::TxTime, &sk_txtime).map_err(|e| {
+        context(
+            e.into(),
+            format!(
+                "unable to set SO_TXTIME with clock {}",
+                txtime::clock_name(clock)
+            ),
+        )
+    })?;
+
+    Ok(socket)
+}
 
-    socket
+/// Prefixes `e` with what was being done, keeping its kind.
+fn context(e: std::io::Error, what: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("{}: {}", what, e))
 }
 
-pub fn send_message(socket: &Socket, message: &[u8], addr: std::net::SocketAddr, txtime: u64) {
+/// Sends `message` to `addr` with the launch time `txtime`, in the clock of
+/// the `SO_TXTIME` socket.
+pub fn send_message(
+    socket: &Socket,
+    message: &[u8],
+    addr: std::net::SocketAddr,
+    txtime: u64,
+) -> std::io::Result<usize> {
     let cmsg = nix::sys::socket::ControlMessage::TxTime(&txtime);
 
     let sockfd = socket.as_raw_fd();
     let iov = std::io::IoSlice::new(message);
-    let localhost = nix::sys::socket::SockaddrIn::from_str(&addr.to_string()).unwrap();
-    sendmsg(
-        sockfd,
-        &[iov],
-        &[cmsg],
-        nix::sys::socket::MsgFlags::empty(),
-        Some(&localhost),
-    )
-    .unwrap();
-
-    let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_REALTIME).unwrap();
+    let addr = nix::sys::socket::SockaddrStorage::from(addr);
+    let sent = sendmsg(sockfd, &[iov], &[cmsg], MsgFlags::empty(), Some(&addr))?;
+
+    Ok(sent)
+}
+
+/// Current CLOCK_REALTIME in nanoseconds, the clock used for message timestamps
+/// and deadlines.
+#[inline]
+pub fn now_ns() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
 }
 
 #[inline]
@@ -70,51 +157,3 @@ pub fn normalize_timestamp_ns(ts: u64, base: u64) -> u64 {
     let tmp = ts / base;
     tmp * base
 }
-
-pub fn calculate_txtime(now: u64, period: u64, txtime: u64) -> u64 {
-    /*
-     * ^
-     * |    threshold
-     * |   <--------->    |
-     * |                  |
-     * |   ^              |
-     * |   |         ^    |
-     * |   |         |    |
-     * +---+---------+----+------------------>
-     *   req_offset  |    |
-     *               |  rt_slot_offset
-     * <---txtime--->|
-     */
-
-    let now_normalized = normalize_timestamp_ns(now, period);
-    let request_offset = now - now_normalized;
-
-    let mut send_time = txtime;
-
-    let rt_start_offset = period - txtime;
-    let rt_end_offset = period - request_offset;
-
-    let dynamic_time = true;
-    let threshold_ns = 10;
-    let has_rt_slot = true;
-    if has_rt_slot {
-        if dynamic_time {
-            send_time = now + threshold_ns;
-        } else {
-            if request_offset < rt_end_offset && request_offset + threshold_ns > txtime {
-                send_time = now_normalized + txtime;
-            } else {
-                // NOTE(garbu): We are in the BE slot, so we add the period
-                //      to the current time in order to send the message
-                //      in the next available window and RT slot.
-                send_time = now_normalized + period + txtime;
-            }
-        }
-    } else {
-        // There is no RT slot.
-        // TODO(garbu): Should we send the message now or in the next period?
-        send_time = now;
-    }
-
-    send_time
-}
@@ -1,17 +1,621 @@
-use crate::buffer::{self, Buffer};
+use crate::buffer::{Buffer, BufferError};
+use crate::{msg_type, priority_class};
 
+/// "TP", first two bytes of every TEMPOS datagram.
+pub const MAGIC: u16 = 0x5450;
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 22;
+/// Largest message the components receive.
+pub const MAX_MESSAGE_LEN: usize = 2048;
+
+/// Largest payload of an INVOK for `topic` that fits in `MAX_MESSAGE_LEN`.
+pub const fn max_invok_data_len(topic: &str) -> usize {
+    MAX_MESSAGE_LEN.saturating_sub(HEADER_LEN + 4 + 2 + 1 + 4 + topic.len() + 4)
+}
+
+/// Header carried by every TEMPOS datagram.
+///
+/// Wire layout (all integers big-endian):
+///
+/// ```text
+/// magic(u16) version(u8) type(u8) flags(u8) priority(u8) timestamp(u64) deadline(u64)
+/// ```
+///
+/// Timestamps are CLOCK_REALTIME nanoseconds (see [`crate::now_ns`]). The
+/// message type is taken from the [`TemposMessage`] variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TemposHeader {
-    pub msg_type: u8,
-    pub timestamp: i64,
+    pub flags: u8,
+    /// One of the `priority_class` constants.
+    pub priority: u8,
+    /// Time at which the message (or the chain it belongs to) was first sent.
+    pub timestamp: u64,
+    /// Absolute deadline, 0 if the message has none.
+    pub deadline: u64,
+}
+
+impl TemposHeader {
+    /// Best-effort header stamped with the current time and no deadline.
+    pub fn now() -> Self {
+        Self {
+            flags: 0,
+            priority: priority_class::BEST_EFFORT,
+            timestamp: crate::now_ns(),
+            deadline: 0,
+        }
+    }
+
+    pub fn has_deadline(&self) -> bool {
+        self.deadline != 0
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.has_deadline() && now > self.deadline
+    }
+
+    /// Time elapsed since the message was first sent.
+    pub fn latency_ns(&self, now: u64) -> u64 {
+        now.saturating_sub(self.timestamp)
+    }
+}
+
+/// A decoded TEMPOS message body.
+///
+/// Topics and payloads borrow from the receive buffer, so decoding never
+/// allocates.
+///
+/// Wire layout after the [`TemposHeader`] (all integers big-endian):
+///
+/// ```text
+/// REGISTRATION:   node_id(u32) topic_len(u32) topic
+/// INVOK:          seq(u32) chain(u16) hop(u8) topic_len(u32) topic data_len(u32) data
+/// MONITORING:     node_id(u32) load(f32) memory(f32) in_flight(u32) warm(u8)
+/// UNREGISTRATION: node_id(u32)
+/// UNSUBSCRIBE:    node_id(u32) topic_len(u32) topic
+/// FAILURE:        seq(u32) chain(u16) hop(u8) kind(u8) status(i32) topic_len(u32) topic reason_len(u32) reason
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemposMessage<'a> {
+    Registration {
+        node_id: u32,
+        topic: &'a str,
+    },
+    /// Invocation of `topic`. `chain` is the id of the function chain the
+    /// invocation belongs to, 0 if none, and `hop` its position in it.
+    Invok {
+        seq: u32,
+        chain: u16,
+        hop: u8,
+        topic: &'a str,
+        data: &'a [u8],
+    },
+    /// Periodic status report of an invoker. `load` and `memory` are
+    /// fractions in `0.0..=1.0`, `warm` tells whether a module is loaded.
+    Monitoring {
+        node_id: u32,
+        load: f32,
+        memory: f32,
+        in_flight: u32,
+        warm: bool,
+    },
+    Unregistration {
+        node_id: u32,
+    },
+    /// Removes a single topic subscription, the node stays registered.
+    Unsubscribe {
+        node_id: u32,
+        topic: &'a str,
+    },
+    /// The invocation `seq` of `topic` failed, sent by the invoker to the
+    /// MOM which reports it to the sender of the INVOK. `kind` is one of
+    /// the `failure` constants, `status` the one returned by the function.
+    Failure {
+        seq: u32,
+        chain: u16,
+        hop: u8,
+        kind: u8,
+        status: i32,
+        topic: &'a str,
+        reason: &'a str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The datagram does not start with [`MAGIC`].
+    BadMagic(u16),
+    /// The peer speaks a protocol version we do not understand.
+    UnsupportedVersion(u8),
+    /// The first byte is not one of the `msg_type` constants.
+    UnknownType(u8),
+    /// A field or a length-prefixed section runs past the end of the datagram.
+    Truncated { needed: usize, available: usize },
+    /// The topic or the reason of a failure is not valid UTF-8.
+    InvalidTopic(std::str::Utf8Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::BadMagic(m) => write!(f, "bad magic 0x{:04x}", m),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported protocol version {}", v)
+            }
+            DecodeError::UnknownType(t) => write!(f, "unknown message type 0x{:02x}", t),
+            DecodeError::Truncated { needed, available } => write!(
+                f,
+                "truncated datagram: needed {} bytes, {} available",
+                needed, available
+            ),
+            DecodeError::InvalidTopic(e) => write!(f, "invalid topic: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
 }
 
-pub struct TemposMessage<'a> {
-    pub header: TemposHeader,
-    buffer: &'a Buffer,
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let available = self.buf.len() - self.pos;
+        if n > available {
+            return Err(DecodeError::Truncated {
+                needed: n,
+                available,
+            });
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let b = self.take(8)?;
+        Ok(u64::from_be_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read_f32(&mut self) -> Result<f32, DecodeError> {
+        let b = self.take(4)?;
+        Ok(f32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    fn read_str(&mut self) -> Result<&'a str, DecodeError> {
+        std::str::from_utf8(self.read_bytes()?).map_err(DecodeError::InvalidTopic)
+    }
 }
 
-// impl<'a> TemposMessage<'a> {
-//     pub fn new(buffer: &Buffer) -> Self {
-//         TemposMessage { buffer, header: () }
-//     }
-// }
+/// Decodes a single datagram. Trailing bytes after the message are ignored.
+pub fn decode(buf: &[u8]) -> Result<(TemposHeader, TemposMessage<'_>), DecodeError> {
+    let mut r = Reader { buf, pos: 0 };
+
+    let magic = r.read_u16()?;
+    if magic != MAGIC {
+        return Err(DecodeError::BadMagic(magic));
+    }
+
+    let version = r.read_u8()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let ty = r.read_u8()?;
+    let header = TemposHeader {
+        flags: r.read_u8()?,
+        priority: r.read_u8()?,
+        timestamp: r.read_u64()?,
+        deadline: r.read_u64()?,
+    };
+
+    let msg = match ty {
+        msg_type::REGISTRATION => TemposMessage::Registration {
+            node_id: r.read_u32()?,
+            topic: r.read_str()?,
+        },
+        msg_type::INVOK => TemposMessage::Invok {
+            seq: r.read_u32()?,
+            chain: r.read_u16()?,
+            hop: r.read_u8()?,
+            topic: r.read_str()?,
+            data: r.read_bytes()?,
+        },
+        msg_type::MONITORING => TemposMessage::Monitoring {
+            node_id: r.read_u32()?,
+            load: r.read_f32()?,
+            memory: r.read_f32()?,
+            in_flight: r.read_u32()?,
+            warm: r.read_u8()? != 0,
+        },
+        msg_type::UNREGISTRATION => TemposMessage::Unregistration {
+            node_id: r.read_u32()?,
+        },
+        msg_type::UNSUBSCRIBE => TemposMessage::Unsubscribe {
+            node_id: r.read_u32()?,
+            topic: r.read_str()?,
+        },
+        msg_type::FAILURE => TemposMessage::Failure {
+            seq: r.read_u32()?,
+            chain: r.read_u16()?,
+            hop: r.read_u8()?,
+            kind: r.read_u8()?,
+            status: r.read_i32()?,
+            topic: r.read_str()?,
+            reason: r.read_str()?,
+        },
+        t => return Err(DecodeError::UnknownType(t)),
+    };
+
+    Ok((header, msg))
+}
+
+impl<'a> TemposMessage<'a> {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            TemposMessage::Registration { .. } => msg_type::REGISTRATION,
+            TemposMessage::Invok { .. } => msg_type::INVOK,
+            TemposMessage::Monitoring { .. } => msg_type::MONITORING,
+            TemposMessage::Unregistration { .. } => msg_type::UNREGISTRATION,
+            TemposMessage::Unsubscribe { .. } => msg_type::UNSUBSCRIBE,
+            TemposMessage::Failure { .. } => msg_type::FAILURE,
+        }
+    }
+
+    /// Size of the encoded message, header included.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                TemposMessage::Registration { topic, .. } => 4 + 4 + topic.len(),
+                TemposMessage::Invok { topic, data, .. } => {
+                    4 + 2 + 1 + 4 + topic.len() + 4 + data.len()
+                }
+                TemposMessage::Monitoring { .. } => 4 + 4 + 4 + 4 + 1,
+                TemposMessage::Unregistration { .. } => 4,
+                TemposMessage::Unsubscribe { topic, .. } => 4 + 4 + topic.len(),
+                TemposMessage::Failure { topic, reason, .. } => {
+                    4 + 2 + 1 + 1 + 4 + 4 + topic.len() + 4 + reason.len()
+                }
+            }
+    }
+
+    /// Appends the wire representation of `header` and the message to `buf`.
+    pub fn encode(&self, header: &TemposHeader, buf: &mut Vec<u8>) {
+        buf.reserve(self.encoded_len());
+        self.encode_with(header, |bytes| buf.extend_from_slice(bytes));
+    }
+
+    /// Appends the message to `buf`, which is left untouched if it does not
+    /// have enough room.
+    pub fn encode_into(&self, header: &TemposHeader, buf: &mut Buffer) -> Result<(), BufferError> {
+        let needed = self.encoded_len();
+        if needed > buf.tailroom() {
+            return Err(BufferError::Overflow {
+                needed,
+                available: buf.tailroom(),
+            });
+        }
+
+        self.encode_with(header, |bytes| {
+            // NOTE: can't fail, the room was checked above.
+            let _ = buf.write_bytes(bytes);
+        });
+        Ok(())
+    }
+
+    fn encode_with(&self, header: &TemposHeader, mut put: impl FnMut(&[u8])) {
+        put(&MAGIC.to_be_bytes());
+        put(&[VERSION, self.msg_type(), header.flags, header.priority]);
+        put(&header.timestamp.to_be_bytes());
+        put(&header.deadline.to_be_bytes());
+
+        match self {
+            TemposMessage::Registration { node_id, topic } => {
+                put(&node_id.to_be_bytes());
+                put_bytes(&mut put, topic.as_bytes());
+            }
+            TemposMessage::Invok {
+                seq,
+                chain,
+                hop,
+                topic,
+                data,
+            } => {
+                put(&seq.to_be_bytes());
+                put(&chain.to_be_bytes());
+                put(&[*hop]);
+                put_bytes(&mut put, topic.as_bytes());
+                put_bytes(&mut put, data);
+            }
+            TemposMessage::Monitoring {
+                node_id,
+                load,
+                memory,
+                in_flight,
+                warm,
+            } => {
+                put(&node_id.to_be_bytes());
+                put(&load.to_be_bytes());
+                put(&memory.to_be_bytes());
+                put(&in_flight.to_be_bytes());
+                put(&[*warm as u8]);
+            }
+            TemposMessage::Unregistration { node_id } => {
+                put(&node_id.to_be_bytes());
+            }
+            TemposMessage::Unsubscribe { node_id, topic } => {
+                put(&node_id.to_be_bytes());
+                put_bytes(&mut put, topic.as_bytes());
+            }
+            TemposMessage::Failure {
+                seq,
+                chain,
+                hop,
+                kind,
+                status,
+                topic,
+                reason,
+            } => {
+                put(&seq.to_be_bytes());
+                put(&chain.to_be_bytes());
+                put(&[*hop, *kind]);
+                put(&status.to_be_bytes());
+                put_bytes(&mut put, topic.as_bytes());
+                put_bytes(&mut put, reason.as_bytes());
+            }
+        }
+    }
+}
+
+/// Writes `bytes` prefixed by their u32 length.
+fn put_bytes(put: &mut impl FnMut(&[u8]), bytes: &[u8]) {
+    put(&(bytes.len() as u32).to_be_bytes());
+    put(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Iterations of each property, the generator is seeded so that a
+    /// failure reproduces.
+    const CASES: usize = 20_000;
+
+    fn header() -> TemposHeader {
+        TemposHeader {
+            flags: crate::flags::SHM_RING,
+            priority: priority_class::STRICT,
+            timestamp: 0x0102_0304_0506_0708,
+            deadline: u64::MAX,
+        }
+    }
+
+    /// One message of every type.
+    fn samples() -> Vec<TemposMessage<'static>> {
+        vec![
+            TemposMessage::Registration {
+                node_id: 7,
+                topic: "vpn",
+            },
+            TemposMessage::Invok {
+                seq: u32::MAX,
+                chain: 3,
+                hop: 2,
+                topic: "enc",
+                data: b"\x00payload\xff",
+            },
+            TemposMessage::Invok {
+                seq: 0,
+                chain: 0,
+                hop: 0,
+                topic: "",
+                data: b"",
+            },
+            TemposMessage::Monitoring {
+                node_id: 9,
+                load: 0.25,
+                memory: 1.0,
+                in_flight: 4,
+                warm: true,
+            },
+            TemposMessage::Unregistration { node_id: 11 },
+            TemposMessage::Unsubscribe {
+                node_id: 12,
+                topic: "dec",
+            },
+            TemposMessage::Failure {
+                seq: 5,
+                chain: 1,
+                hop: 4,
+                kind: crate::failure::STATUS,
+                status: -1,
+                topic: "dcp",
+                reason: "returned status -1",
+            },
+        ]
+    }
+
+    fn encoded(msg: &TemposMessage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        msg.encode(&header(), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn every_message_type_round_trips() {
+        for msg in samples() {
+            let buf = encoded(&msg);
+            assert_eq!(buf.len(), msg.encoded_len(), "{:?}", msg);
+            assert_eq!(decode(&buf), Ok((header(), msg.clone())));
+        }
+    }
+
+    #[test]
+    fn encode_into_matches_encode() {
+        for msg in samples() {
+            let mut buf = Buffer::with_capacity(MAX_MESSAGE_LEN, 0);
+            msg.encode_into(&header(), &mut buf).unwrap();
+            assert_eq!(buf.as_slice(), encoded(&msg).as_slice());
+        }
+    }
+
+    #[test]
+    fn max_invok_data_len_fills_a_message() {
+        for topic in ["", "vpn", "a-much-longer-topic-name"] {
+            let data = vec![0xa5; max_invok_data_len(topic)];
+            let msg = TemposMessage::Invok {
+                seq: 1,
+                chain: 2,
+                hop: 3,
+                topic,
+                data: &data,
+            };
+            assert_eq!(msg.encoded_len(), MAX_MESSAGE_LEN);
+            let mut buf = Buffer::with_capacity(MAX_MESSAGE_LEN, 0);
+            msg.encode_into(&header(), &mut buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn encode_into_leaves_a_short_buffer_untouched() {
+        for msg in samples() {
+            let mut buf = Buffer::with_capacity(msg.encoded_len() - 1, 0);
+            assert!(msg.encode_into(&header(), &mut buf).is_err());
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_ignores_trailing_bytes() {
+        for msg in samples() {
+            let mut buf = encoded(&msg);
+            buf.extend_from_slice(b"trailing");
+            assert_eq!(decode(&buf), Ok((header(), msg)));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_every_truncation() {
+        for msg in samples() {
+            let buf = encoded(&msg);
+            for len in 0..buf.len() {
+                assert!(
+                    matches!(decode(&buf[..len]), Err(DecodeError::Truncated { .. })),
+                    "{:?} truncated to {} bytes",
+                    msg,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rejects_bad_magic_version_and_type() {
+        let buf = encoded(&samples()[0]);
+
+        let mut bad = buf.clone();
+        bad[0] ^= 0xff;
+        assert!(matches!(decode(&bad), Err(DecodeError::BadMagic(_))));
+
+        let mut bad = buf.clone();
+        bad[2] = VERSION + 1;
+        assert_eq!(
+            decode(&bad),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut bad = buf;
+        bad[3] = 0xee;
+        assert_eq!(decode(&bad), Err(DecodeError::UnknownType(0xee)));
+    }
+
+    #[test]
+    fn decode_rejects_huge_lengths() {
+        // NOTE: a registration whose topic claims u32::MAX bytes.
+        let mut buf = encoded(&samples()[0]);
+        buf[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(decode(&buf), Err(DecodeError::Truncated { .. })));
+    }
+
+    #[test]
+    fn decode_rejects_invalid_utf8_topics() {
+        let mut buf = encoded(&samples()[0]);
+        buf[HEADER_LEN + 8] = 0xff;
+        assert!(matches!(decode(&buf), Err(DecodeError::InvalidTopic(_))));
+    }
+
+    #[test]
+    fn decode_never_panics_on_arbitrary_bytes() {
+        let mut rng = StdRng::seed_from_u64(0x5450);
+        let mut buf = Vec::new();
+        for _ in 0..CASES {
+            buf.clear();
+            buf.resize(rng.gen_range(0..128), 0);
+            rng.fill(buf.as_mut_slice());
+            let _ = decode(&buf);
+        }
+    }
+
+    #[test]
+    fn decode_never_panics_past_a_valid_preamble() {
+        // NOTE: arbitrary bytes rarely start with the magic and version, the
+        //       bodies of every type are only reached with a valid preamble.
+        let mut rng = StdRng::seed_from_u64(0x5451);
+        let mut buf = Vec::new();
+        for _ in 0..CASES {
+            buf.clear();
+            buf.extend_from_slice(&MAGIC.to_be_bytes());
+            buf.push(VERSION);
+            buf.push(rng.gen_range(0..=msg_type::FAILURE + 1));
+            let start = buf.len();
+            buf.resize(start + rng.gen_range(0..96), 0);
+            rng.fill(&mut buf[start..]);
+            let _ = decode(&buf);
+        }
+    }
+
+    #[test]
+    fn decode_never_panics_on_mutated_messages() {
+        let mut rng = StdRng::seed_from_u64(0x5452);
+        let samples: Vec<Vec<u8>> = samples().iter().map(encoded).collect();
+        for _ in 0..CASES {
+            let mut buf = samples[rng.gen_range(0..samples.len())].clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..buf.len());
+                buf[i] = rng.gen();
+            }
+            buf.truncate(rng.gen_range(0..=buf.len()));
+            let _ = decode(&buf);
+        }
+    }
+}
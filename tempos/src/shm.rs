@@ -0,0 +1,493 @@
+//! Shared-memory ring used by the MOM to hand messages to invokers running
+//! on the same host, without going through the network stack.
+//!
+//! The ring is a bounded MPSC queue of fixed-size slots (Vyukov's algorithm)
+//! in a file under `/dev/shm`. The invoker creates it and is the only
+//! consumer, every MOM lane can produce into it. A consumer that does not
+//! busy-poll sleeps on the FIFO next to the ring (`<ring>.wake`), announced
+//! through the `waiting` flag of the header: producers only write to the
+//! FIFO while the flag is set.
+//!
+//! Memory layout:
+//!
+//! ```text
+//! RingHeader (3 cache lines) | slot 0 | slot 1 | ... | slot n-1
+//! slot: seq(u64) len(u32) pad(u32) data[slot_size], padded to a cache line
+//! ```
+
+use std::{
+    ffi::CString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    ops::Deref,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::buffer::{Buffer, BufferError};
+
+/// "TPRG"
+const RING_MAGIC: u32 = 0x54505247;
+const RING_VERSION: u32 = 2;
+const CACHE_LINE: usize = 64;
+const SLOT_HEADER_LEN: usize = 16;
+
+pub const DEFAULT_SLOTS: u32 = 256;
+pub const DEFAULT_SLOT_SIZE: u32 = crate::message::MAX_MESSAGE_LEN as u32;
+
+/// Well-known location of the ring of invoker `node_id`.
+pub fn ring_path(node_id: u32) -> PathBuf {
+    PathBuf::from(format!("/dev/shm/tempos-node-{}.ring", node_id))
+}
+
+/// FIFO on which the consumer of the ring at `path` waits for messages.
+fn wake_path(path: &Path) -> PathBuf {
+    path.with_extension("wake")
+}
+
+#[repr(C, align(64))]
+struct RingHeader {
+    /// Written last by the creator, once the slots are initialized.
+    magic: AtomicU32,
+    version: u32,
+    slots: u32,
+    slot_size: u32,
+    /// Set while the consumer sleeps on the wakeup FIFO.
+    waiting: AtomicU32,
+    _pad0: [u8; CACHE_LINE - 20],
+    /// Next position to produce.
+    head: AtomicU64,
+    _pad1: [u8; CACHE_LINE - 8],
+    /// Next position to consume.
+    tail: AtomicU64,
+    _pad2: [u8; CACHE_LINE - 8],
+}
+
+#[repr(C)]
+struct SlotHeader {
+    /// `pos` when the slot is free for position `pos`, `pos + 1` once the
+    /// message of position `pos` is ready.
+    seq: AtomicU64,
+    len: u32,
+    _pad: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    Full,
+    TooLarge(usize),
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Full => write!(f, "ring is full"),
+            PushError::TooLarge(len) => write!(f, "message of {} bytes exceeds the slot size", len),
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+pub struct ShmRing {
+    base: *mut u8,
+    len: usize,
+    slots: u64,
+    slot_size: usize,
+    stride: usize,
+    /// Read end of the wakeup FIFO for the consumer, write end for producers.
+    wake: File,
+    /// Set for the creator, which removes the files on drop.
+    owned: Option<PathBuf>,
+}
+
+impl fmt::Debug for ShmRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShmRing")
+            .field("slots", &self.slots)
+            .field("slot_size", &self.slot_size)
+            .finish()
+    }
+}
+
+// NOTE: all the shared state is accessed through atomics or through slots
+//       owned by a single thread according to their sequence number.
+unsafe impl Send for ShmRing {}
+unsafe impl Sync for ShmRing {}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn stride(slot_size: usize) -> usize {
+    (SLOT_HEADER_LEN + slot_size).div_ceil(CACHE_LINE) * CACHE_LINE
+}
+
+fn map(file: &File, len: usize) -> io::Result<*mut u8> {
+    let base = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(base as *mut u8)
+}
+
+impl ShmRing {
+    /// Creates (or replaces) the ring at `path`. `slots` must be a power of two.
+    pub fn create(path: &Path, slots: u32, slot_size: u32) -> io::Result<Self> {
+        if !slots.is_power_of_two() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("ring slots must be a power of two, got {}", slots),
+            ));
+        }
+
+        let stride = stride(slot_size as usize);
+        let len = std::mem::size_of::<RingHeader>() + stride * slots as usize;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o660)
+            .open(path)?;
+        file.set_len(len as u64)?;
+
+        // NOTE: the FIFO is opened for writing too, so that it never reports
+        //       end of file once a producer goes away.
+        let wake = wake_path(path);
+        let _ = std::fs::remove_file(&wake);
+        let cpath = CString::new(wake.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::mkfifo(cpath.as_ptr(), 0o660) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let ring = Self {
+            base: map(&file, len)?,
+            len,
+            slots: slots as u64,
+            slot_size: slot_size as usize,
+            stride,
+            wake: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&wake)?,
+            owned: Some(path.to_owned()),
+        };
+
+        // NOTE: the file is zero-filled, only the non-zero fields are written.
+        let header = ring.base as *mut RingHeader;
+        unsafe {
+            (*header).version = RING_VERSION;
+            (*header).slots = slots;
+            (*header).slot_size = slot_size;
+        }
+        for pos in 0..ring.slots {
+            ring.slot(pos).seq.store(pos, Ordering::Relaxed);
+        }
+        ring.header().magic.store(RING_MAGIC, Ordering::Release);
+
+        Ok(ring)
+    }
+
+    /// Attaches to the ring created at `path` by its consumer.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < std::mem::size_of::<RingHeader>() {
+            return Err(invalid(format!("{}: too small for a ring", path.display())));
+        }
+
+        let mut ring = Self {
+            base: map(&file, len)?,
+            len,
+            slots: 0,
+            slot_size: 0,
+            stride: 0,
+            wake: OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(wake_path(path))?,
+            owned: None,
+        };
+
+        let header = ring.header();
+        if header.magic.load(Ordering::Acquire) != RING_MAGIC {
+            return Err(invalid(format!("{}: not a TEMPOS ring", path.display())));
+        }
+        if header.version != RING_VERSION {
+            return Err(invalid(format!(
+                "{}: unsupported ring version {}",
+                path.display(),
+                header.version
+            )));
+        }
+
+        let (slots, slot_size) = (header.slots, header.slot_size);
+        let stride = stride(slot_size as usize);
+        if !slots.is_power_of_two()
+            || len != std::mem::size_of::<RingHeader>() + stride * slots as usize
+        {
+            return Err(invalid(format!(
+                "{}: corrupted ring header",
+                path.display()
+            )));
+        }
+
+        ring.slots = slots as u64;
+        ring.slot_size = slot_size as usize;
+        ring.stride = stride;
+
+        Ok(ring)
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.base as *const RingHeader) }
+    }
+
+    fn slot_ptr(&self, pos: u64) -> *mut u8 {
+        let idx = (pos & (self.slots - 1)) as usize;
+        unsafe {
+            self.base
+                .add(std::mem::size_of::<RingHeader>() + idx * self.stride)
+        }
+    }
+
+    fn slot(&self, pos: u64) -> &SlotHeader {
+        unsafe { &*(self.slot_ptr(pos) as *const SlotHeader) }
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Copies `msg` into the next free slot. Safe to call from several
+    /// threads or processes at once.
+    pub fn push(&self, msg: &[u8]) -> Result<(), PushError> {
+        if msg.len() > self.slot_size {
+            return Err(PushError::TooLarge(msg.len()));
+        }
+
+        let head = &self.header().head;
+        let mut pos = head.load(Ordering::Relaxed);
+        loop {
+            let seq = self.slot(pos).seq.load(Ordering::Acquire);
+            match seq.cmp(&pos) {
+                std::cmp::Ordering::Equal => {
+                    match head.compare_exchange_weak(
+                        pos,
+                        pos + 1,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(current) => pos = current,
+                    }
+                }
+                // NOTE: the slot still holds the message of the previous lap.
+                std::cmp::Ordering::Less => return Err(PushError::Full),
+                std::cmp::Ordering::Greater => pos = head.load(Ordering::Relaxed),
+            }
+        }
+
+        let slot = self.slot_ptr(pos);
+        unsafe {
+            ptr::copy_nonoverlapping(msg.as_ptr(), slot.add(SLOT_HEADER_LEN), msg.len());
+            (*(slot as *mut SlotHeader)).len = msg.len() as u32;
+        }
+        self.slot(pos).seq.store(pos + 1, Ordering::Release);
+
+        // NOTE: pairs with the fence of prepare_wait, either the consumer
+        //       sees the message or this sees the flag.
+        fence(Ordering::SeqCst);
+        if self.header().waiting.load(Ordering::Relaxed) != 0 {
+            // NOTE: a full FIFO already holds a pending wakeup.
+            let _ = (&self.wake).write(&[1]);
+        }
+
+        Ok(())
+    }
+
+    /// Borrows the next message in place, the slot is released when the
+    /// returned guard is dropped.
+    ///
+    /// Must only be called by the single consumer of the ring.
+    pub fn next(&self) -> Option<RingSlot<'_>> {
+        let pos = self.header().tail.load(Ordering::Relaxed);
+        let slot = self.slot(pos);
+        if slot.seq.load(Ordering::Acquire) != pos + 1 {
+            return None;
+        }
+
+        let len = (slot.len as usize).min(self.slot_size);
+        let data =
+            unsafe { std::slice::from_raw_parts(self.slot_ptr(pos).add(SLOT_HEADER_LEN), len) };
+
+        Some(RingSlot {
+            ring: self,
+            pos,
+            data,
+        })
+    }
+
+    /// Hands the next message to `f` without copying it out of the ring.
+    /// The slot is released when `f` returns.
+    ///
+    /// Must only be called by the single consumer of the ring.
+    pub fn consume<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        self.next().map(|slot| f(&slot))
+    }
+
+    /// Moves the next message into `buf`, returns false if the ring is empty.
+    /// A message that does not fit in `buf` is consumed anyway.
+    pub fn pop(&self, buf: &mut Buffer) -> Result<bool, BufferError> {
+        match self.consume(|msg| buf.set_data(msg)) {
+            Some(res) => res.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let pos = self.header().tail.load(Ordering::Relaxed);
+        self.slot(pos).seq.load(Ordering::Acquire) != pos + 1
+    }
+
+    /// File descriptor that becomes readable when a message is pushed
+    /// between `prepare_wait` and `finish_wait`. Consumer only.
+    pub fn wakeup_fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+
+    /// Asks the producers for a wakeup. Returns false, and cancels the
+    /// request, if a message is already waiting. Consumer only.
+    pub fn prepare_wait(&self) -> bool {
+        let waiting = &self.header().waiting;
+        waiting.store(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if self.is_empty() {
+            return true;
+        }
+
+        waiting.store(0, Ordering::Relaxed);
+        false
+    }
+
+    /// Withdraws the wakeup request and drains the FIFO. Consumer only.
+    pub fn finish_wait(&self) {
+        self.header().waiting.store(0, Ordering::Relaxed);
+        let mut drain = [0; 64];
+        while matches!((&self.wake).read(&mut drain), Ok(n) if n > 0) {}
+    }
+}
+
+/// Message borrowed from the ring by its consumer.
+pub struct RingSlot<'a> {
+    ring: &'a ShmRing,
+    pos: u64,
+    data: &'a [u8],
+}
+
+impl Deref for RingSlot<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl Drop for RingSlot<'_> {
+    fn drop(&mut self) {
+        let ring = self.ring;
+        ring.slot(self.pos)
+            .seq
+            .store(self.pos + ring.slots, Ordering::Release);
+        ring.header().tail.store(self.pos + 1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.len);
+        }
+        if let Some(path) = &self.owned {
+            let _ = std::fs::remove_file(path);
+            let _ = std::fs::remove_file(wake_path(path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_ring(name: &str, slots: u32) -> ShmRing {
+        let path =
+            std::env::temp_dir().join(format!("tempos-{}-{}.ring", name, std::process::id()));
+        ShmRing::create(&path, slots, 64).unwrap()
+    }
+
+    fn readable(fd: RawFd) -> bool {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn next_releases_the_slot_on_drop() {
+        let ring = temp_ring("next", 2);
+        let producer = ShmRing::open(ring.owned.as_ref().unwrap()).unwrap();
+
+        producer.push(b"one").unwrap();
+        producer.push(b"two").unwrap();
+        assert_eq!(producer.push(b"three"), Err(PushError::Full));
+
+        {
+            let slot = ring.next().unwrap();
+            assert_eq!(&*slot, b"one");
+            assert_eq!(producer.push(b"three"), Err(PushError::Full));
+        }
+        producer.push(b"three").unwrap();
+
+        assert_eq!(ring.consume(|msg| msg.to_vec()).unwrap(), b"two");
+        assert_eq!(&*ring.next().unwrap(), b"three");
+        assert!(ring.next().is_none());
+    }
+
+    #[test]
+    fn push_wakes_a_waiting_consumer() {
+        let ring = temp_ring("wake", 4);
+        let producer = ShmRing::open(ring.owned.as_ref().unwrap()).unwrap();
+
+        // NOTE: nobody waits, pushes leave the FIFO alone.
+        producer.push(b"early").unwrap();
+        assert!(!readable(ring.wakeup_fd()));
+        assert!(!ring.prepare_wait());
+        ring.next().unwrap();
+
+        assert!(ring.prepare_wait());
+        producer.push(b"late").unwrap();
+        assert!(readable(ring.wakeup_fd()));
+        ring.finish_wait();
+        assert!(!readable(ring.wakeup_fd()));
+        assert_eq!(&*ring.next().unwrap(), b"late");
+    }
+}
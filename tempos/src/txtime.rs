@@ -0,0 +1,537 @@
+//! Time-based transmission: messages handed to an etf/taprio qdisc with
+//! the `SCM_TXTIME` launch time computed from the taprio cycle and the
+//! message deadline.
+
+use nix::time::{clock_gettime, ClockId};
+use socket2::Socket;
+use std::{ffi::CString, io, mem, net::SocketAddr, os::unix::prelude::*};
+
+/// Qdiscs honouring `SCM_TXTIME`. taprio only does with `txtime-assist`.
+const TXTIME_QDISCS: [&str; 2] = ["etf", "taprio"];
+
+/// `struct tcmsg` from `linux/rtnetlink.h`.
+#[repr(C)]
+struct TcMsg {
+    family: u8,
+    _pad1: u8,
+    _pad2: u16,
+    ifindex: i32,
+    handle: u32,
+    parent: u32,
+    info: u32,
+}
+
+const TCA_KIND: u16 = 1;
+
+/// `linux/errqueue.h`, not in libc yet.
+const SO_EE_ORIGIN_TXTIME: u8 = 6;
+const SO_EE_CODE_TXTIME_INVALID_PARAM: u8 = 1;
+const SO_EE_CODE_TXTIME_MISSED: u8 = 2;
+
+/// Clocks a `SO_TXTIME` socket can schedule on, named as in `tc`.
+const CLOCKS: [(&str, ClockId); 3] = [
+    ("CLOCK_TAI", ClockId::CLOCK_TAI),
+    ("CLOCK_REALTIME", ClockId::CLOCK_REALTIME),
+    ("CLOCK_MONOTONIC", ClockId::CLOCK_MONOTONIC),
+];
+
+/// The clock named `name`, e.g. `CLOCK_TAI`.
+pub fn clock_from_name(name: &str) -> io::Result<ClockId> {
+    CLOCKS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, clock)| clock)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown clock '{}', expected one of {}",
+                    name,
+                    CLOCKS.map(|(n, _)| n).join(", ")
+                ),
+            )
+        })
+}
+
+/// The `tc` name of `clock`, its id when unknown.
+pub fn clock_name(clock: ClockId) -> String {
+    CLOCKS
+        .iter()
+        .find(|&&(_, c)| c == clock)
+        .map_or_else(|| clock.to_string(), |(n, _)| n.to_string())
+}
+
+/// Launch-time reports of the qdisc, read from the socket error queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TxTimeErrors {
+    /// Messages dequeued after their launch time, dropped.
+    pub missed: u64,
+    /// Messages rejected on enqueue: launch time in the past or in a
+    /// different clock than the qdisc.
+    pub invalid: u64,
+}
+
+impl TxTimeErrors {
+    pub fn is_empty(&self) -> bool {
+        self.missed == 0 && self.invalid == 0
+    }
+}
+
+/// Drains the error queue of a [`crate::open_socket`] socket, counting the
+/// `SO_EE_ORIGIN_TXTIME` reports. Other errors are discarded.
+pub fn read_errors(socket: &Socket) -> io::Result<TxTimeErrors> {
+    let mut errors = TxTimeErrors::default();
+    // NOTE: the payload of the dropped message is returned with the report,
+    //       only the control message matters.
+    let mut data = [0u8; 64];
+    let mut control = [0u64; 16];
+
+    loop {
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of_val(&control);
+
+        let n = unsafe {
+            libc::recvmsg(
+                socket.as_raw_fd(),
+                &mut hdr,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(errors),
+                _ => Err(e),
+            };
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level, kind) == (libc::SOL_IP, libc::IP_RECVERR)
+                || (level, kind) == (libc::SOL_IPV6, libc::IPV6_RECVERR)
+            {
+                let ee = unsafe {
+                    (libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err).read_unaligned()
+                };
+                if ee.ee_origin == SO_EE_ORIGIN_TXTIME {
+                    match ee.ee_code {
+                        SO_EE_CODE_TXTIME_MISSED => errors.missed += 1,
+                        SO_EE_CODE_TXTIME_INVALID_PARAM => errors.invalid += 1,
+                        _ => {}
+                    }
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+        }
+    }
+}
+
+fn nl_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Kinds of the qdiscs attached to `iface`, e.g. `["mqprio", "etf"]`.
+pub fn qdisc_kinds(iface: &str) -> io::Result<Vec<String>> {
+    let name = CString::new(iface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    #[repr(C)]
+    struct Request {
+        hdr: libc::nlmsghdr,
+        tcm: TcMsg,
+    }
+    let req = Request {
+        hdr: libc::nlmsghdr {
+            nlmsg_len: mem::size_of::<Request>() as u32,
+            nlmsg_type: libc::RTM_GETQDISC,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        },
+        tcm: TcMsg {
+            family: libc::AF_UNSPEC as u8,
+            _pad1: 0,
+            _pad2: 0,
+            ifindex: 0,
+            handle: 0,
+            parent: 0,
+            info: 0,
+        },
+    };
+    let sent = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            &req as *const _ as *const libc::c_void,
+            mem::size_of::<Request>(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut kinds = vec![];
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        let n = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut msgs = &buf[..n as usize];
+        while msgs.len() >= mem::size_of::<libc::nlmsghdr>() {
+            let hdr = unsafe { (msgs.as_ptr() as *const libc::nlmsghdr).read_unaligned() };
+            let len = hdr.nlmsg_len as usize;
+            if len < mem::size_of::<libc::nlmsghdr>() || len > msgs.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                ));
+            }
+
+            match hdr.nlmsg_type as libc::c_int {
+                libc::NLMSG_DONE => return Ok(kinds),
+                libc::NLMSG_ERROR => {
+                    let errno = unsafe {
+                        (msgs.as_ptr().add(mem::size_of::<libc::nlmsghdr>()) as *const i32)
+                            .read_unaligned()
+                    };
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                _ if hdr.nlmsg_type == libc::RTM_NEWQDISC => {
+                    let body = &msgs[nl_align(mem::size_of::<libc::nlmsghdr>())..len];
+                    if let Some(kind) = parse_qdisc(body, ifindex as i32) {
+                        kinds.push(kind);
+                    }
+                }
+                _ => {}
+            }
+
+            msgs = &msgs[nl_align(len).min(msgs.len())..];
+        }
+    }
+}
+
+/// The `TCA_KIND` of a `RTM_NEWQDISC` body, if it belongs to `ifindex`.
+fn parse_qdisc(body: &[u8], ifindex: i32) -> Option<String> {
+    if body.len() < mem::size_of::<TcMsg>() {
+        return None;
+    }
+    let tcm = unsafe { (body.as_ptr() as *const TcMsg).read_unaligned() };
+    if tcm.ifindex != ifindex {
+        return None;
+    }
+
+    let mut attrs = &body[nl_align(mem::size_of::<TcMsg>())..];
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let kind = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if len < 4 || len > attrs.len() {
+            return None;
+        }
+        if kind == TCA_KIND {
+            let value = &attrs[4..len];
+            let value = &value[..value.iter().position(|&b| b == 0).unwrap_or(value.len())];
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+        attrs = &attrs[nl_align(len).min(attrs.len())..];
+    }
+
+    None
+}
+
+/// Time for a message to travel from `sendmsg` to the qdisc. Launch times
+/// already past on enqueue are rejected, so none is closer to now than this.
+const LAUNCH_LEAD_NS: u64 = 20_000;
+
+/// Launch times of a traffic class in a taprio schedule: the window of the
+/// class starts `offset_ns` into every `cycle_ns` cycle and lasts
+/// `window_ns`, messages are only launched while at least `guard_ns` of it
+/// remain so that they leave before the gate closes.
+///
+/// ```text
+/// base + k * cycle_ns
+/// |<-- offset -->|<------------- window ------------->|
+/// +--------------+-------------------------+----------+------+
+/// |              |       launch now        |  guard   |      |
+/// +--------------+-------------------------+----------+------+
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct TxTimeScheduler {
+    base_ns: u64,
+    cycle_ns: u64,
+    offset_ns: u64,
+    window_ns: u64,
+    guard_ns: u64,
+}
+
+impl TxTimeScheduler {
+    /// Fails unless the window starts within the cycle, fits in it and is
+    /// longer than its guard band. A window may wrap around the end of the
+    /// cycle.
+    pub fn new(cycle_ns: u64, offset_ns: u64, window_ns: u64, guard_ns: u64) -> io::Result<Self> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if cycle_ns == 0 {
+            return invalid("empty taprio cycle".to_string());
+        }
+        if offset_ns >= cycle_ns {
+            return invalid(format!(
+                "window offset {} ns outside of the {} ns cycle",
+                offset_ns, cycle_ns
+            ));
+        }
+        if window_ns == 0 || window_ns > cycle_ns {
+            return invalid(format!(
+                "window of {} ns does not fit in the {} ns cycle",
+                window_ns, cycle_ns
+            ));
+        }
+        if guard_ns >= window_ns {
+            return invalid(format!(
+                "guard band of {} ns leaves nothing of the {} ns window",
+                guard_ns, window_ns
+            ));
+        }
+
+        Ok(Self {
+            base_ns: 0,
+            cycle_ns,
+            offset_ns,
+            window_ns,
+            guard_ns,
+        })
+    }
+
+    /// Start of the schedule, the taprio `base-time`. Defaults to 0, i.e.
+    /// cycles aligned on multiples of `cycle_ns`.
+    pub fn with_base_time(mut self, base_ns: u64) -> Self {
+        self.base_ns = base_ns;
+        self
+    }
+
+    /// Earliest launch time from `now` inside the window: `now` itself when
+    /// the window is open, the next opening otherwise.
+    pub fn launch_time(&self, now: u64) -> u64 {
+        // NOTE: before the base time the schedule is extended backwards, as
+        //       taprio does.
+        let cycle = self.cycle_ns as i128;
+        let pos = (now as i128 - self.base_ns as i128).rem_euclid(cycle) as u64;
+        let since_open = (pos + self.cycle_ns - self.offset_ns) % self.cycle_ns;
+
+        if since_open < self.window_ns - self.guard_ns {
+            now
+        } else {
+            now + (self.cycle_ns - since_open)
+        }
+    }
+
+    /// Like `launch_time`, `None` if the window only opens after `latest`.
+    pub fn launch_time_by(&self, now: u64, latest: u64) -> Option<u64> {
+        Some(self.launch_time(now)).filter(|&launch| launch <= latest)
+    }
+}
+
+/// A socket sending through the etf/taprio qdisc of an interface.
+pub struct TxTimeSocket {
+    socket: Socket,
+    clock: ClockId,
+    /// Window of the traffic class of the socket priority.
+    scheduler: TxTimeScheduler,
+}
+
+impl TxTimeSocket {
+    /// Opens a `SO_TXTIME` socket with priority `priority` on `iface`,
+    /// launching in the windows of `scheduler` on `clock` (the `clockid` of
+    /// the qdisc). Fails if the
+    /// interface has no etf/taprio qdisc, the launch times would then be
+    /// ignored.
+    pub fn open(
+        priority: i32,
+        iface: &str,
+        clock: ClockId,
+        scheduler: TxTimeScheduler,
+    ) -> io::Result<Self> {
+        let kinds = qdisc_kinds(iface)?;
+        if !kinds
+            .iter()
+            .any(|kind| TXTIME_QDISCS.contains(&kind.as_str()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} has no etf or taprio qdisc (found: {})",
+                    iface,
+                    if kinds.is_empty() {
+                        "none".to_string()
+                    } else {
+                        kinds.join(", ")
+                    }
+                ),
+            ));
+        }
+
+        let socket = crate::open_socket(priority, iface, clock)?;
+
+        Ok(Self {
+            socket,
+            clock,
+            scheduler,
+        })
+    }
+
+    /// Launch time, in the socket clock, of a message with the given
+    /// `deadline` (CLOCK_REALTIME ns, 0 for none): the next window of the
+    /// socket priority. Fails with `TimedOut` if the deadline passes before
+    /// the window opens, launching outside of it would bypass the schedule.
+    pub fn txtime(&self, deadline: u64) -> io::Result<u64> {
+        let now = clock_gettime(self.clock)?;
+        let now = now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64;
+
+        if deadline == 0 {
+            return Ok(self.scheduler.launch_time(now + LAUNCH_LEAD_NS));
+        }
+
+        let remaining = deadline.saturating_sub(crate::now_ns());
+        self.scheduler
+            .launch_time_by(now + LAUNCH_LEAD_NS, now + remaining)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("deadline in {} ns, before the next window", remaining),
+                )
+            })
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr, deadline: u64) -> io::Result<usize> {
+        let txtime = self.txtime(deadline)?;
+        crate::send_message(&self.socket, buf, addr, txtime)
+    }
+
+    /// Launch-time errors reported since the last call.
+    pub fn errors(&self) -> io::Result<TxTimeErrors> {
+        read_errors(&self.socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(res: io::Result<TxTimeScheduler>, msg: &str) {
+        let e = res.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains(msg), "{}", e);
+    }
+
+    #[test]
+    fn new_rejects_invalid_schedules() {
+        rejected(TxTimeScheduler::new(0, 0, 1, 0), "empty taprio cycle");
+        rejected(TxTimeScheduler::new(1000, 1000, 100, 0), "outside of the");
+        rejected(TxTimeScheduler::new(1000, 0, 0, 0), "does not fit");
+        rejected(TxTimeScheduler::new(1000, 0, 1001, 0), "does not fit");
+        rejected(TxTimeScheduler::new(1000, 0, 100, 100), "leaves nothing");
+        rejected(TxTimeScheduler::new(1000, 0, 100, 200), "leaves nothing");
+
+        assert!(TxTimeScheduler::new(1000, 999, 1000, 999).is_ok());
+    }
+
+    #[test]
+    fn launch_time_in_window() {
+        // NOTE: window [200, 500) of a 1000 ns cycle, launching until 450.
+        let s = TxTimeScheduler::new(1000, 200, 300, 50).unwrap();
+
+        assert_eq!(s.launch_time(200), 200);
+        assert_eq!(s.launch_time(449), 449);
+        assert_eq!(s.launch_time(5_300), 5_300);
+    }
+
+    #[test]
+    fn launch_time_outside_window() {
+        let s = TxTimeScheduler::new(1000, 200, 300, 50).unwrap();
+
+        assert_eq!(s.launch_time(0), 200);
+        assert_eq!(s.launch_time(199), 200);
+        assert_eq!(s.launch_time(500), 1_200);
+        assert_eq!(s.launch_time(5_999), 6_200);
+    }
+
+    #[test]
+    fn launch_time_in_guard_band() {
+        let s = TxTimeScheduler::new(1000, 200, 300, 50).unwrap();
+
+        assert_eq!(s.launch_time(450), 1_200);
+        assert_eq!(s.launch_time(499), 1_200);
+    }
+
+    #[test]
+    fn launch_time_wrapping_window() {
+        // NOTE: window [800, 1100), i.e. [800, 1000) and [0, 100) of the
+        //       next cycle, launching until 1050.
+        let s = TxTimeScheduler::new(1000, 800, 300, 50).unwrap();
+
+        assert_eq!(s.launch_time(900), 900);
+        assert_eq!(s.launch_time(1_000), 1_000);
+        assert_eq!(s.launch_time(1_049), 1_049);
+        assert_eq!(s.launch_time(1_050), 1_800);
+        assert_eq!(s.launch_time(1_500), 1_800);
+    }
+
+    #[test]
+    fn launch_time_by_deadline() {
+        let s = TxTimeScheduler::new(1000, 200, 300, 50).unwrap();
+
+        // NOTE: in the window, the launch is now even right at the deadline.
+        assert_eq!(s.launch_time_by(300, 300), Some(300));
+        // NOTE: outside of it, a deadline before the next opening is never
+        //       moved ahead of the window.
+        assert_eq!(s.launch_time_by(100, 199), None);
+        assert_eq!(s.launch_time_by(100, 200), Some(200));
+        assert_eq!(s.launch_time_by(460, 1_000), None);
+        assert_eq!(s.launch_time_by(460, 5_000), Some(1_200));
+    }
+
+    #[test]
+    fn launch_time_with_base_time() {
+        let s = TxTimeScheduler::new(1000, 200, 300, 50)
+            .unwrap()
+            .with_base_time(10_050);
+
+        assert_eq!(s.launch_time(10_250), 10_250);
+        assert_eq!(s.launch_time(10_500), 11_250);
+        // NOTE: the schedule extends backwards before the base time.
+        assert_eq!(s.launch_time(9_300), 9_300);
+        assert_eq!(s.launch_time(9_100), 9_250);
+        assert_eq!(s.launch_time(0), 250);
+    }
+}
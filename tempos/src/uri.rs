@@ -0,0 +1,272 @@
+//! Transport URIs of the MOM tasks and the components:
+//!
+//! ```text
+//! udp://127.0.0.1:3333?priority=3&iface=eth0
+//! unix:///run/tempos/mom.sock
+//! unix+seqpacket:///run/tempos/task0.sock
+//! tcp://10.0.0.2:4000
+//! tap://tap0
+//! ```
+//!
+//! A bare `ip:port` is accepted as `udp://ip:port`, and the legacy
+//! `udp/ip:port` form as `udp://ip:port`. The scheme is only checked for
+//! its syntax, the users of the URI decide which ones they support.
+
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+    Empty,
+    InvalidScheme(String),
+    MissingTarget,
+    InvalidAddress(String),
+    InvalidParam(String),
+    DuplicateParam(String),
+    /// Key, value and why the value was rejected.
+    InvalidValue(String, String, String),
+}
+
+impl fmt::Display for UriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UriError::Empty => write!(f, "empty uri"),
+            UriError::InvalidScheme(s) => write!(f, "invalid scheme '{}'", s),
+            UriError::MissingTarget => write!(f, "missing address, path or interface"),
+            UriError::InvalidAddress(a) => write!(f, "invalid address '{}', expected ip:port", a),
+            UriError::InvalidParam(p) => write!(f, "invalid parameter '{}'", p),
+            UriError::DuplicateParam(p) => write!(f, "parameter '{}' is repeated", p),
+            UriError::InvalidValue(key, value, e) => {
+                write!(f, "invalid value '{}' for '{}': {}", value, key, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UriError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    /// Lowercase scheme, e.g. `udp`.
+    pub scheme: String,
+    /// `host:port` for network schemes, a path for `unix`, an interface for `tap`.
+    pub target: String,
+    pub params: HashMap<String, String>,
+}
+
+impl Uri {
+    /// Parses the parameter `key`, `None` if it is not set.
+    pub fn param<T>(&self, key: &str) -> Result<Option<T>, UriError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.params.get(key) {
+            Some(value) => value.parse().map(Some).map_err(|e: T::Err| {
+                UriError::InvalidValue(key.to_string(), value.to_string(), e.to_string())
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Schemes whose target is an `ip:port`.
+const NETWORK_SCHEMES: [&str; 2] = ["udp", "tcp"];
+
+impl FromStr for Uri {
+    type Err = UriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(UriError::Empty);
+        }
+
+        let (head, query) = s.split_once('?').unwrap_or((s, ""));
+
+        let (scheme, target) = if head.parse::<SocketAddr>().is_ok() {
+            ("udp".to_string(), head)
+        } else {
+            // NOTE: the legacy form separates the scheme with a slash.
+            let sep = head
+                .find([':', '/'])
+                .ok_or_else(|| UriError::InvalidScheme(head.to_string()))?;
+            let (scheme, rest) = (&head[..sep], &head[sep + 1..]);
+
+            let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+            if !valid {
+                return Err(UriError::InvalidScheme(scheme.to_string()));
+            }
+
+            (
+                scheme.to_ascii_lowercase(),
+                rest.strip_prefix("//").unwrap_or(rest),
+            )
+        };
+
+        if target.is_empty() {
+            return Err(UriError::MissingTarget);
+        }
+        if NETWORK_SCHEMES.contains(&scheme.as_str()) && target.parse::<SocketAddr>().is_err() {
+            return Err(UriError::InvalidAddress(target.to_string()));
+        }
+
+        let mut params = HashMap::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key.is_empty() {
+                return Err(UriError::InvalidParam(pair.to_string()));
+            }
+            if params.insert(key.to_string(), value.to_string()).is_some() {
+                return Err(UriError::DuplicateParam(key.to_string()));
+            }
+        }
+
+        Ok(Uri {
+            scheme,
+            target: target.to_string(),
+            params,
+        })
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.target)?;
+
+        let mut params: Vec<_> = self.params.iter().collect();
+        params.sort();
+        for (i, (key, value)) in params.into_iter().enumerate() {
+            let sep = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}={}", sep, key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(scheme: &str, target: &str, params: &[(&str, &str)]) -> Uri {
+        Uri {
+            scheme: scheme.to_string(),
+            target: target.to_string(),
+            params: params
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parse_udp() {
+        let expected = uri("udp", "127.0.0.1:3333", &[]);
+        assert_eq!("127.0.0.1:3333".parse(), Ok(expected.clone()));
+        assert_eq!("udp://127.0.0.1:3333".parse(), Ok(expected.clone()));
+        assert_eq!("UDP://127.0.0.1:3333".parse(), Ok(expected.clone()));
+        assert_eq!(" udp:127.0.0.1:3333 ".parse(), Ok(expected));
+        assert_eq!("[::1]:3333".parse(), Ok(uri("udp", "[::1]:3333", &[])));
+    }
+
+    #[test]
+    fn parse_legacy() {
+        assert_eq!(
+            "udp/127.0.0.1:8001?sink=127.0.0.1:8002".parse(),
+            Ok(uri("udp", "127.0.0.1:8001", &[("sink", "127.0.0.1:8002")]))
+        );
+        assert_eq!(
+            "tcp/10.0.0.2:4000".parse(),
+            Ok(uri("tcp", "10.0.0.2:4000", &[]))
+        );
+    }
+
+    #[test]
+    fn parse_unix() {
+        let expected = uri("unix", "/run/tempos/mom.sock", &[]);
+        assert_eq!("unix:///run/tempos/mom.sock".parse(), Ok(expected.clone()));
+        assert_eq!("unix:/run/tempos/mom.sock".parse(), Ok(expected));
+        assert_eq!(
+            "unix+seqpacket:///run/tempos/task0.sock".parse(),
+            Ok(uri("unix+seqpacket", "/run/tempos/task0.sock", &[]))
+        );
+        assert_eq!("tap://tap0".parse(), Ok(uri("tap", "tap0", &[])));
+    }
+
+    #[test]
+    fn parse_params() {
+        assert_eq!(
+            "udp://127.0.0.1:3333?priority=3&iface=eth0&&flag".parse(),
+            Ok(uri(
+                "udp",
+                "127.0.0.1:3333",
+                &[("priority", "3"), ("iface", "eth0"), ("flag", "")]
+            ))
+        );
+        assert_eq!(
+            "udp://127.0.0.1:3333?=3".parse::<Uri>(),
+            Err(UriError::InvalidParam("=3".to_string()))
+        );
+        assert_eq!(
+            "udp://127.0.0.1:3333?priority=3&priority=4".parse::<Uri>(),
+            Err(UriError::DuplicateParam("priority".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("".parse::<Uri>(), Err(UriError::Empty));
+        assert_eq!("  ".parse::<Uri>(), Err(UriError::Empty));
+        assert_eq!(
+            "nothing".parse::<Uri>(),
+            Err(UriError::InvalidScheme("nothing".to_string()))
+        );
+        assert_eq!(
+            "1udp://127.0.0.1:3333".parse::<Uri>(),
+            Err(UriError::InvalidScheme("1udp".to_string()))
+        );
+        assert_eq!("unix://".parse::<Uri>(), Err(UriError::MissingTarget));
+        assert_eq!(
+            "udp://127.0.0.1:99999".parse::<Uri>(),
+            Err(UriError::InvalidAddress("127.0.0.1:99999".to_string()))
+        );
+        assert_eq!(
+            "tcp://10.0.0.2:http".parse::<Uri>(),
+            Err(UriError::InvalidAddress("10.0.0.2:http".to_string()))
+        );
+        assert_eq!(
+            "udp://127.0.0.1".parse::<Uri>(),
+            Err(UriError::InvalidAddress("127.0.0.1".to_string()))
+        );
+    }
+
+    #[test]
+    fn param_values() {
+        let uri: Uri = "udp://127.0.0.1:3333?priority=3&iface=eth0"
+            .parse()
+            .unwrap();
+        assert_eq!(uri.param::<i32>("priority"), Ok(Some(3)));
+        assert_eq!(uri.param::<String>("iface"), Ok(Some("eth0".to_string())));
+        assert_eq!(uri.param::<i32>("missing"), Ok(None));
+        assert!(matches!(
+            uri.param::<i32>("iface"),
+            Err(UriError::InvalidValue(key, value, _)) if key == "iface" && value == "eth0"
+        ));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "udp://127.0.0.1:3333?iface=eth0&priority=3",
+            "unix:///run/tempos/mom.sock",
+            "tap://tap0",
+        ] {
+            let uri: Uri = s.parse().unwrap();
+            assert_eq!(uri.to_string(), s);
+            assert_eq!(uri.to_string().parse(), Ok(uri));
+        }
+    }
+}
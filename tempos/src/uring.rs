@@ -0,0 +1,404 @@
+//! io_uring backend for datagram sockets, enabled by the `uring` feature.
+//!
+//! Receive side: a single multishot `recvmsg` stays armed on the socket and
+//! the kernel picks the buffers from a ring of provided buffers registered
+//! with `IORING_REGISTER_PBUF_RING`, so an idle socket costs no syscall and
+//! a busy one delivers many datagrams per `io_uring_enter`.
+//!
+//! Send side: every [`UringSocket::send_to`] fills a preallocated slot and
+//! queues a `sendmsg`. The queued sends are submitted by the next
+//! [`UringSocket::recv`] together with the wait for the next datagram.
+//!
+//! Requires Linux 6.0 (multishot `recvmsg`).
+
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use socket2::SockAddr;
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    io,
+    mem::{self, MaybeUninit},
+    os::unix::prelude::*,
+    path::PathBuf,
+    ptr,
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use crate::buffer::Buffer;
+use crate::endpoint::PeerAddr;
+use crate::message::MAX_MESSAGE_LEN;
+
+pub const DEFAULT_RECV_BUFFERS: u16 = 256;
+pub const DEFAULT_SEND_SLOTS: usize = 64;
+
+const BUF_GROUP: u16 = 0;
+const RECV: u64 = u64::MAX;
+/// `io_uring_recvmsg_out` + the source address + the datagram.
+const RECV_BUF_LEN: usize = 16 + mem::size_of::<libc::sockaddr_storage>() + MAX_MESSAGE_LEN;
+
+/// The provided buffer ring: `entries` descriptors shared with the kernel
+/// and the memory they point to.
+struct BufRing {
+    entries: *mut types::BufRingEntry,
+    mask: u16,
+    tail: u16,
+    mem: Vec<u8>,
+}
+
+impl BufRing {
+    fn new(count: u16) -> io::Result<Self> {
+        // NOTE: the descriptors must be page aligned.
+        let len = count as usize * mem::size_of::<types::BufRingEntry>();
+        let entries = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if entries == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ring = Self {
+            entries: entries as *mut types::BufRingEntry,
+            mask: count - 1,
+            tail: 0,
+            mem: vec![0; count as usize * RECV_BUF_LEN],
+        };
+        for bid in 0..count {
+            ring.recycle(bid);
+        }
+
+        Ok(ring)
+    }
+
+    fn buf(&self, bid: u16) -> &[u8] {
+        let start = bid as usize * RECV_BUF_LEN;
+        &self.mem[start..start + RECV_BUF_LEN]
+    }
+
+    /// Gives buffer `bid` back to the kernel.
+    fn recycle(&mut self, bid: u16) {
+        let addr = self.buf(bid).as_ptr() as u64;
+        unsafe {
+            let entry = &mut *self.entries.add((self.tail & self.mask) as usize);
+            entry.set_addr(addr);
+            entry.set_len(RECV_BUF_LEN as u32);
+            entry.set_bid(bid);
+        }
+        self.tail = self.tail.wrapping_add(1);
+
+        // NOTE: the tail overlaps the first descriptor, the kernel reads it
+        //       with acquire semantics.
+        let tail = unsafe { &*(types::BufRingEntry::tail(self.entries) as *const AtomicU16) };
+        tail.store(self.tail, Ordering::Release);
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        let len = (self.mask as usize + 1) * mem::size_of::<types::BufRingEntry>();
+        unsafe {
+            libc::munmap(self.entries as *mut libc::c_void, len);
+        }
+    }
+}
+
+/// A pending `sendmsg`, its header points into the slot itself.
+struct SendSlot {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    addr: libc::sockaddr_storage,
+    buf: Box<[u8]>,
+}
+
+pub struct UringSocket {
+    // NOTE: dropped first, so the kernel is done with the buffers below.
+    ring: IoUring,
+    bufs: BufRing,
+    recv_hdr: Box<libc::msghdr>,
+    recv_armed: bool,
+    send_slots: Vec<SendSlot>,
+    free_slots: Vec<usize>,
+    completed: VecDeque<cqueue::Entry>,
+    send_errors: u64,
+}
+
+// NOTE: the raw pointers only refer to memory owned by the socket itself.
+unsafe impl Send for UringSocket {}
+
+fn to_sockaddr(peer: &PeerAddr) -> io::Result<SockAddr> {
+    match peer {
+        PeerAddr::Udp(addr) => Ok(SockAddr::from(*addr)),
+        PeerAddr::Unix(path) => SockAddr::unix(path),
+    }
+}
+
+fn from_sockaddr(name: &[u8]) -> Option<PeerAddr> {
+    if name.len() < mem::size_of::<libc::sa_family_t>()
+        || name.len() > mem::size_of::<libc::sockaddr_storage>()
+    {
+        return None;
+    }
+
+    let mut storage: libc::sockaddr_storage = unsafe { MaybeUninit::zeroed().assume_init() };
+    unsafe {
+        ptr::copy_nonoverlapping(name.as_ptr(), &mut storage as *mut _ as *mut u8, name.len());
+    }
+
+    if storage.ss_family as libc::c_int == libc::AF_UNIX {
+        // NOTE: unbound and abstract senders have no path to reply to.
+        let path = &name[mem::size_of::<libc::sa_family_t>()..];
+        let path = &path[..path.iter().position(|&b| b == 0).unwrap_or(path.len())];
+        if path.is_empty() {
+            return None;
+        }
+        return Some(PeerAddr::Unix(PathBuf::from(OsStr::from_bytes(path))));
+    }
+
+    let addr = unsafe { SockAddr::new(storage, name.len() as libc::socklen_t) };
+    addr.as_socket().map(PeerAddr::Udp)
+}
+
+impl UringSocket {
+    /// Drives the datagram socket `sock` (UDP or Unix datagram) through a new
+    /// ring. `recv_buffers` must be a power of two.
+    ///
+    /// The socket is registered with the ring, which keeps it open: it must
+    /// not be read from or written to directly anymore.
+    pub fn new(sock: &impl AsRawFd, recv_buffers: u16, send_slots: usize) -> io::Result<Self> {
+        if !recv_buffers.is_power_of_two() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "receive buffers must be a power of two, got {}",
+                    recv_buffers
+                ),
+            ));
+        }
+
+        let ring = IoUring::new((send_slots as u32 + 1).next_power_of_two())?;
+        ring.submitter().register_files(&[sock.as_raw_fd()])?;
+
+        let bufs = BufRing::new(recv_buffers)?;
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                bufs.entries as u64,
+                recv_buffers,
+                BUF_GROUP,
+                0,
+            )?;
+        }
+
+        let mut recv_hdr: Box<libc::msghdr> =
+            Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
+        recv_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let mut send_slots: Vec<SendSlot> = (0..send_slots)
+            .map(|_| SendSlot {
+                hdr: unsafe { MaybeUninit::zeroed().assume_init() },
+                iov: libc::iovec {
+                    iov_base: ptr::null_mut(),
+                    iov_len: 0,
+                },
+                addr: unsafe { MaybeUninit::zeroed().assume_init() },
+                buf: vec![0; MAX_MESSAGE_LEN].into_boxed_slice(),
+            })
+            .collect();
+        // NOTE: the vector is never resized, so these pointers stay valid.
+        for slot in &mut send_slots {
+            slot.iov.iov_base = slot.buf.as_mut_ptr() as *mut libc::c_void;
+            slot.hdr.msg_iov = &mut slot.iov;
+            slot.hdr.msg_iovlen = 1;
+            slot.hdr.msg_name = &mut slot.addr as *mut _ as *mut libc::c_void;
+        }
+
+        let mut uring = Self {
+            completed: VecDeque::with_capacity(ring.params().cq_entries() as usize),
+            ring,
+            bufs,
+            recv_hdr,
+            recv_armed: false,
+            free_slots: (0..send_slots.len()).rev().collect(),
+            send_slots,
+            send_errors: 0,
+        };
+        uring.arm_recv()?;
+
+        Ok(uring)
+    }
+
+    /// Sends that failed so far.
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors
+    }
+
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        // NOTE: the submission queue is sized for every send slot plus the
+        //       receive, it can only be full before the queued entries are
+        //       submitted.
+        while unsafe { self.ring.submission().push(entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    fn arm_recv(&mut self) -> io::Result<()> {
+        let entry = opcode::RecvMsgMulti::new(types::Fixed(0), &*self.recv_hdr, BUF_GROUP)
+            .build()
+            .user_data(RECV);
+        self.push(&entry)?;
+        self.recv_armed = true;
+        Ok(())
+    }
+
+    /// Moves the available completions to `completed`. Send completions are
+    /// handled right away, so their slots can be reused.
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            if entry.user_data() == RECV {
+                self.completed.push_back(entry);
+                continue;
+            }
+
+            if entry.result() < 0 {
+                self.send_errors += 1;
+            }
+            self.free_slots.push(entry.user_data() as usize);
+        }
+    }
+
+    /// Queues `buf` for `peer`. It is sent by the next [`UringSocket::recv`]
+    /// or [`UringSocket::flush`].
+    pub fn send_to(&mut self, buf: &[u8], peer: &PeerAddr) -> io::Result<()> {
+        let addr = to_sockaddr(peer)?;
+
+        let idx = loop {
+            if let Some(idx) = self.free_slots.pop() {
+                break idx;
+            }
+            // NOTE: every slot is in flight, wait for one to complete.
+            self.ring.submit_and_wait(1)?;
+            self.reap();
+        };
+
+        let slot = &mut self.send_slots[idx];
+        let len = buf.len().min(slot.buf.len());
+        slot.buf[..len].copy_from_slice(&buf[..len]);
+        slot.iov.iov_len = len;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                addr.as_ptr() as *const u8,
+                &mut slot.addr as *mut _ as *mut u8,
+                addr.len() as usize,
+            );
+        }
+        slot.hdr.msg_namelen = addr.len();
+
+        let entry = opcode::SendMsg::new(types::Fixed(0), &slot.hdr)
+            .build()
+            .user_data(idx as u64);
+        self.push(&entry)?;
+
+        if len < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message of {} bytes truncated to {}", buf.len(), len),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Submits the queued sends without waiting.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.ring.submit()?;
+        self.reap();
+        Ok(())
+    }
+
+    /// Replaces the content of `buf` with the next datagram and returns its
+    /// sender. Waits up to `timeout` and fails with `WouldBlock` if nothing
+    /// arrived, like a nonblocking socket.
+    pub fn recv(&mut self, buf: &mut Buffer, timeout: Duration) -> io::Result<Option<PeerAddr>> {
+        loop {
+            if let Some(entry) = self.completed.pop_front() {
+                if let Some(received) = self.complete_recv(&entry, buf)? {
+                    return Ok(received);
+                }
+                continue;
+            }
+
+            if !self.recv_armed {
+                self.arm_recv()?;
+            }
+
+            let ts = types::Timespec::from(timeout);
+            let args = types::SubmitArgs::new().timespec(&ts);
+            match self.ring.submitter().submit_with_args(1, &args) {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::ETIME) => {
+                    self.reap();
+                    if self.completed.is_empty() {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => {}
+                Err(e) => return Err(e),
+            }
+            self.reap();
+        }
+    }
+
+    /// Handles a receive completion: `Ok(None)` if it carried no datagram.
+    fn complete_recv(
+        &mut self,
+        entry: &cqueue::Entry,
+        buf: &mut Buffer,
+    ) -> io::Result<Option<Option<PeerAddr>>> {
+        if !cqueue::more(entry.flags()) {
+            self.recv_armed = false;
+        }
+
+        let res = entry.result();
+        let bid = cqueue::buffer_select(entry.flags());
+        if res < 0 {
+            // NOTE: ENOBUFS only means all the buffers were in use, the
+            //       receive is armed again on the next call.
+            if res == -libc::ENOBUFS {
+                return Ok(None);
+            }
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        let bid = match bid {
+            Some(bid) => bid,
+            None => return Ok(None),
+        };
+
+        let data = &self.bufs.buf(bid)[..res as usize];
+        let received = match types::RecvMsgOut::parse(data, &self.recv_hdr) {
+            Ok(out) if out.is_payload_truncated() => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "datagram larger than {} bytes truncated",
+                    out.payload_data().len()
+                ),
+            )),
+            Ok(out) => buf
+                .set_data(out.payload_data())
+                .map(|_| Some(from_sockaddr(out.name_data())))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(()) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed multishot recvmsg completion",
+            )),
+        };
+        self.bufs.recycle(bid);
+
+        received
+    }
+}